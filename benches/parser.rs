@@ -0,0 +1,95 @@
+#[macro_use] extern crate criterion;
+extern crate household_power;
+
+use std::collections::HashMap;
+use std::hint::black_box;
+
+use criterion::{Criterion, Throughput};
+
+use household_power::{EventStream, Parser, envi_parser, run_parser_into};
+use household_power::Parser::*;
+
+/// Number of messages in each benchmarked stream.
+const NUM_MESSAGES: usize = 1000;
+
+// The same messages that the parser's tests check the values of.
+const LIVE: &str = include_str!("../tests/fixtures/live.xml");
+
+const LIVE_NO_TEMPERATURE: &str = include_str!("../tests/fixtures/live_no_temperature.xml");
+
+/// From a monitor reporting tenths of a watt.
+const LIVE_DECIMAL: &str = include_str!("../tests/fixtures/live_decimal.xml");
+
+/// With the sensor's identity in attributes, rather than in tags of its own.
+const LIVE_ATTRIBUTES: &str = include_str!("../tests/fixtures/live_attributes.xml");
+
+/// With the optional tags sent, but empty.
+const LIVE_EMPTY_TAGS: &str = include_str!("../tests/fixtures/live_empty_tags.xml");
+
+/// With the readings wrapped in CDATA sections.
+const LIVE_CDATA: &str = include_str!("../tests/fixtures/live_cdata.xml");
+
+/// Pretty-printed, as by a monitor that indents its output, or after reformatting.
+const LIVE_INDENTED: &str = include_str!("../tests/fixtures/live_indented.xml");
+
+const HISTORY: &str = include_str!("../tests/fixtures/history.xml");
+
+fn attributes_parser() -> Parser {
+    Top("msg", vec![
+        Attribute("src", "src"),
+        Contents("time", "time"),
+        Tag("sensor", vec![Attribute("id", "sensor_id")]),
+        Tag("ch1", vec![Contents("watts", "total")])
+    ])
+}
+
+fn no_temperature_parser() -> Parser {
+    Top("msg", vec![
+        Contents("time", "time"),
+        Tag("ch1", vec![Contents("watts", "total")]),
+        Tag("ch2", vec![Contents("watts", "hot_water")]),
+        Tag("ch3", vec![Contents("watts", "solar")])
+    ])
+}
+
+/// Concatenate `NUM_MESSAGES` messages, cycling through the given shapes.
+fn stream_of(shapes: &[&str]) -> Vec<u8> {
+    shapes.iter().cycle().take(NUM_MESSAGES).flat_map(|msg| msg.bytes()).collect()
+}
+
+/// Parse every message in the stream, returning the number parsed successfully.
+fn parse_all(input: &[u8], parser: &Parser) -> usize {
+    let mut src = EventStream::new(input);
+    let mut data = HashMap::new();
+    let mut parsed = 0;
+    for _ in 0..NUM_MESSAGES {
+        if run_parser_into(&mut src, parser, &mut data).is_ok() {
+            parsed += 1;
+        }
+        black_box(&data);
+    }
+    parsed
+}
+
+fn bench_parser(c: &mut Criterion) {
+    let shapes: Vec<(&str, Vec<u8>, Parser)> = vec![
+        ("live", stream_of(&[LIVE]), envi_parser()),
+        ("live_no_temperature", stream_of(&[LIVE_NO_TEMPERATURE]), no_temperature_parser()),
+        ("live_decimal", stream_of(&[LIVE_DECIMAL]), envi_parser()),
+        ("live_empty_tags", stream_of(&[LIVE_EMPTY_TAGS]), envi_parser()),
+        ("live_cdata", stream_of(&[LIVE_CDATA]), envi_parser()),
+        ("live_indented", stream_of(&[LIVE_INDENTED]), envi_parser()),
+        ("live_attributes", stream_of(&[LIVE_ATTRIBUTES]), attributes_parser()),
+        ("live_and_history", stream_of(&[LIVE, LIVE, LIVE, HISTORY]), envi_parser()),
+    ];
+
+    let mut group = c.benchmark_group("run_parser");
+    group.throughput(Throughput::Elements(NUM_MESSAGES as u64));
+    for &(name, ref input, ref parser) in &shapes {
+        group.bench_function(name, |b| b.iter(|| parse_all(black_box(input), parser)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parser);
+criterion_main!(benches);
@@ -0,0 +1,17 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate household_power;
+
+use household_power::{EventStream, envi_parser, run_parser};
+
+fuzz_target!(|data: &[u8]| {
+    let parser = envi_parser();
+    let mut src = EventStream::new(data);
+
+    // Every parse consumes at least one event, or fails straight away once the stream has
+    // ended, so this many steps is always enough to reach the end of the input. A parse
+    // that never returns shows up as a timeout.
+    for _ in 0..data.len() + 1 {
+        let _ = run_parser(&mut src, &parser);
+    }
+});
@@ -0,0 +1,39 @@
+use std::io;
+
+use xml::reader::Error as XmlError;
+
+/// Everything that can go wrong while reading from the sensor and forwarding its readings.
+///
+/// The variants are chosen by what the main loop should do about them.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The serial port failed, or its stream ended. Reconnect.
+    #[error("{0}")]
+    Serial(#[from] serial::Error),
+    /// No data arrived before the serial port timed out. Reconnect, in case the device has
+    /// gone away.
+    #[error("Timed out waiting for data")]
+    Timeout,
+    /// The XML stream is malformed. Resync on the next message.
+    #[error("{0}")]
+    Xml(#[from] XmlError),
+    /// A message didn't match the parser, or had bad values. Resync on the next message,
+    /// unless there have been too many in a row.
+    #[error("{0}")]
+    Parse(String),
+    /// A reading couldn't be sent to a backend. The reading is lost, but carry on.
+    #[error("{0}")]
+    Backend(String),
+    /// The configuration is invalid. Abort.
+    #[error("{0}")]
+    Config(String)
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        match e.kind() {
+            io::ErrorKind::TimedOut => Error::Timeout,
+            _ => Error::Serial(e.into())
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! The command run once a night, on a thread of its own, as from the `[export]` config.
+
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use chrono::{Days, Local, NaiveTime, TimeZone};
+
+use crate::config::ExportConfig;
+
+/// Start running the command every night at the configured time, until `shutdown` is set.
+///
+/// Whether each run succeeded is logged, a failure as an error. A failure is left until the
+/// next night, without affecting reading.
+pub fn spawn(config: &ExportConfig, shutdown: Arc<AtomicBool>) -> JoinHandle<()> {
+    // The config's been validated, so this is always the time given.
+    let at = config.time().unwrap_or(NaiveTime::MIN);
+    let command = config.command.clone();
+    thread::spawn(move || {
+        loop {
+            let deadline = Instant::now() + until(at);
+            while Instant::now() < deadline {
+                if shutdown.load(Ordering::SeqCst) {
+                    return;
+                }
+                thread::sleep((deadline - Instant::now()).min(Duration::from_millis(100)));
+            }
+            run(&command);
+        }
+    })
+}
+
+fn run(command: &[String]) {
+    let line = command.join(" ");
+    match Command::new(&command[0]).args(&command[1..]).status() {
+        Ok(status) if status.success() => info!("Export finished: {}", line),
+        Ok(status) => error!("Export failed, with {}: {}", status, line),
+        Err(e) => error!("Can't run export {}: {}", line, e)
+    }
+}
+
+/// How long until `at`, later today or tomorrow. A time skipped by a DST change is
+/// waited for on the next day it exists.
+fn until(at: NaiveTime) -> Duration {
+    let now = Local::now();
+    let mut date = now.date_naive();
+    for _ in 0..3 {
+        let next = Local.from_local_datetime(&date.and_time(at)).earliest();
+        if let Some(next) = next.filter(|&next| next > now) {
+            return (next - now).to_std().unwrap_or_default();
+        }
+        date = match date.checked_add_days(Days::new(1)) {
+            Some(date) => date,
+            None => break
+        };
+    }
+    Duration::from_secs(24 * 60 * 60)
+}
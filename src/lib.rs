@@ -0,0 +1,28 @@
+extern crate xml;
+extern crate festivus_client;
+extern crate serial;
+extern crate thiserror;
+#[macro_use] extern crate log;
+
+pub use config::Config;
+pub use error::Error;
+pub use parser::{Parser, EventStream, ENVI_CHANNELS, envi_parser, envi_parser_for};
+pub use parser::parser_for_paths;
+pub use parser::{run_parser, run_parser_into};
+pub use reading::{Applied, Deltas, Overproduction, Pipeline, Reading};
+
+/// The crate version and the git commit it was built from.
+pub const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), " (", env!("GIT_HASH"), ")");
+
+pub mod config;
+mod error;
+pub mod export;
+mod parser;
+mod reading;
+pub mod schema;
+pub mod sink;
+#[cfg(feature = "stream")]
+pub mod stream;
+pub mod summary;
+#[cfg(feature = "tui")]
+pub mod tui;
@@ -1,216 +1,1099 @@
-extern crate xml;
 extern crate serial;
-#[macro_use] extern crate log;
-extern crate festivus_client;
+extern crate household_power;
 
-use std::path::Path;
-use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::io::{self, BufWriter, IsTerminal, Read, Write};
 use std::collections::HashMap;
-use std::error::Error;
-
-use xml::EventReader;
-use xml::reader::XmlEvent;
-use xml::reader::XmlEvent::*;
-use xml::reader::Error as XmlError;
-use xml::name::OwnedName;
+use std::env;
+use std::fs::{self, File};
+use std::mem;
+use std::fmt::Display;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::fs::FileTypeExt;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
 
+use clap::{Parser as ArgParser, Subcommand, ValueEnum};
+use log::{Level, LevelFilter, Log, Metadata, Record};
 use serial::prelude::*;
 use serial::posix::TTYPort;
 use serial::PortSettings;
 use serial::BaudRate::*;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use festivus_client::Festivus;
+use household_power::{Config, Error, EventStream, Parser, Pipeline, Reading};
+use household_power::VERSION;
+use household_power::config::{ParseErrorAction, QueueConfig, SummaryConfig, Units};
+use household_power::export;
+use household_power::sink::{ArchiveSink, CsvSink, FestivusSink, JsonlSink, MultiSink,
+                             ParseErrors, PrometheusSink, PushgatewaySink, QueueStats,
+                             QueuedSink, Sink};
+use household_power::summary::{self, DaySummary};
+#[cfg(feature = "tui")]
+use household_power::tui::{self, Dashboard};
 
-use Parser::*;
+/// Read power use from a CurrentCost Envi, and send it to Festivus.
+#[derive(ArgParser)]
+#[command(version = VERSION, args_conflicts_with_subcommands = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// TOML config file. Everything has a default without one.
+    config: Option<PathBuf>,
+    /// How to show readings as they arrive.
+    #[arg(long, value_enum, default_value_t = Output::Lines)]
+    output: Output,
+    /// Whether to colour the output. Auto colours it if stdout is a terminal and
+    /// NO_COLOR isn't set.
+    #[arg(long, value_enum, default_value_t = Color::Auto)]
+    color: Color,
+    /// Also save every byte read from the serial port to this file, replacing it, as a
+    /// capture to replay or send with a bug report.
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+    /// Instead of reading from the serial port, read some made-up messages for the config,
+    /// check every value in them is read, and exit.
+    #[arg(long)]
+    self_test: bool,
+    /// Print the config in effect, with every default filled in, and exit.
+    #[arg(long)]
+    print_config: bool,
+    /// HTTP proxy to push through, like http://proxy:3128, in place of the one in
+    /// HTTP_PROXY. NO_PROXY still applies.
+    #[arg(long, value_name = "URL")]
+    proxy: Option<String>
+}
 
-const ONE_DAY: u64 = 60 * 60 * 24;
+#[derive(Subcommand)]
+enum Command {
+    /// Check that the instance running with this config has read from the sensor lately,
+    /// from the metrics it serves, for monitoring scripts. Exits 0 if it has, 2 if it
+    /// hasn't, or 3 if it can't tell.
+    Check {
+        /// TOML config file, which must have a [prometheus] section.
+        config: Option<PathBuf>,
+        /// The most seconds since the last reading to count as healthy, by default the
+        /// config's prometheus.stale_after.
+        #[arg(long, value_name = "SECONDS")]
+        max_age: Option<u64>
+    }
+}
 
-/// Convert a String to a Box<Error>.
-fn string_error<T>(s: String) -> Result<T, Box<Error>> {
-    let err: Box<Error + Send + Sync> = From::from(s);
-    Err(err as Box<Error>)
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Output {
+    /// Every reading on a line of its own.
+    Lines,
+    /// A table of the latest reading, updated in place. Falls back to lines if stdout
+    /// isn't a terminal.
+    Table,
+    /// A live dashboard, with a chart of recent readings. Press q to quit.
+    #[cfg(feature = "tui")]
+    Tui
 }
 
-enum Parser {
-    Top(&'static str, Vec<Parser>),
-    Tag(&'static str, Vec<Parser>),
-    Contents(&'static str, &'static str)
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Color {
+    Auto,
+    Always,
+    Never
 }
 
-impl Parser {
-    fn tag_name(&self) -> &'static str {
-        match *self {
-            Top(x, _) | Tag(x, _) | Contents(x, _) => x
+/// How serious a message is, which picks its colour.
+#[derive(Clone, Copy)]
+enum Style {
+    /// Readings, in green.
+    Normal,
+    /// Stale readings and dropped ones, in yellow.
+    Warning,
+    /// Parse errors and failures, in red.
+    Error
+}
+
+/// Warnings and errors logged by the library, as by the queues and the nightly export on
+/// their own threads, held until the console shows them, so that they don't land in the
+/// middle of the table or the dashboard.
+struct Logged(Mutex<Vec<(Style, String)>>);
+
+static LOGGED: Logged = Logged(Mutex::new(vec![]));
+
+impl Logged {
+    fn take(&self) -> Vec<(Style, String)> {
+        mem::take(&mut *self.0.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Print what's left once the console's finished with, like the queues' last words.
+    fn print(&self) {
+        for (_, message) in self.take() {
+            println!("{}", message);
+        }
+    }
+}
+
+impl Log for Logged {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Warn
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let style = if record.level() == Level::Error { Style::Error } else { Style::Warning };
+            let message = record.args().to_string();
+            self.0.lock().unwrap_or_else(|e| e.into_inner()).push((style, message));
         }
     }
+
+    fn flush(&self) {}
 }
 
-trait EventReaderExt {
-    /// Next important tag.
-    fn next_tag(&mut self) -> Result<XmlEvent, XmlError>;
-    /// Consume tags until the given end tag is reached.
-    fn read_to_tag_end(&mut self, tag: &str);
+/// Shows readings and messages on stdout.
+struct Console {
+    output: Output,
+    color: bool,
+    /// The table currently on screen, to be redrawn after messages.
+    table: Vec<String>,
+    /// Whether the reading in the table is out of date.
+    stale: bool,
+    units: Units,
+    /// What the dashboard shows, if that's the output.
+    #[cfg(feature = "tui")]
+    dashboard: Arc<Mutex<Dashboard>>
 }
 
-impl<T> EventReaderExt for EventReader<T> where T: Read {
-    fn next_tag(&mut self) -> Result<XmlEvent, XmlError> {
-        self.next().and_then(|tag| {
-            match tag {
-                // Ignored tag types.
-                StartDocument { .. } |
-                ProcessingInstruction { .. } |
-                CData(..) |
-                Comment(..) |
-                Whitespace(..) => self.next_tag(),
-                // Anything else (not ignored).
-                x => {
-                    info!("Read tag: {:?}", x);
-                    Ok(x)
-                }
+impl Console {
+    fn new(output: Output, color: Color, units: &Units) -> Console {
+        let terminal = io::stdout().is_terminal();
+        let output = if output == Output::Table && !terminal {
+            Output::Lines
+        } else {
+            output
+        };
+        let color = match color {
+            // NO_COLOR only counts if it's set to something.
+            Color::Auto => terminal && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()),
+            Color::Always => true,
+            Color::Never => false
+        };
+        Console {
+            output,
+            color,
+            table: vec![],
+            stale: false,
+            units: units.clone(),
+            #[cfg(feature = "tui")]
+            dashboard: Arc::new(Mutex::new(Dashboard::new(units)))
+        }
+    }
+
+    fn reading(&mut self, reading: &Reading) {
+        self.stale = false;
+        match self.output {
+            Output::Lines => println!("{}", self.paint(Style::Normal, format!("{:?}", reading))),
+            Output::Table => {
+                self.clear_table();
+                self.table = table(reading, &self.units);
+                self.draw_table();
             }
-        })
+            #[cfg(feature = "tui")]
+            Output::Tui => self.dashboard.lock().unwrap().reading(reading)
+        }
     }
 
-    fn read_to_tag_end(&mut self, tag: &str) {
-        loop {
-            // FIXME: infinite loop on error?
-            if let Ok(EndElement { ref name, .. }) = self.next_tag() {
-                if &name.local_name[..] == tag {
-                    info!("Closed </{}>", tag);
-                    break;
-                }
+    /// Show a message, above the table if there is one.
+    fn message<T: Display>(&mut self, style: Style, message: T) {
+        #[cfg(feature = "tui")]
+        {
+            if self.output == Output::Tui {
+                let error = matches!(style, Style::Error);
+                self.dashboard.lock().unwrap().message(message.to_string(), error);
+                return;
             }
         }
+        self.clear_table();
+        println!("{}", self.paint(style, message));
+        self.draw_table();
+    }
+
+    /// Show what the library has logged since last time.
+    fn logged(&mut self) {
+        for (style, message) in LOGGED.take() {
+            self.message(style, message);
+        }
+    }
+
+    /// Show a message saying the latest reading is out of date.
+    fn stale<T: Display>(&mut self, message: T) {
+        self.stale = true;
+        self.message(Style::Warning, message);
+    }
+
+    fn paint<T: Display>(&self, style: Style, text: T) -> String {
+        if !self.color {
+            return text.to_string();
+        }
+        let code = match style {
+            Style::Normal => 32,
+            Style::Warning => 33,
+            Style::Error => 31
+        };
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    }
+
+    fn clear_table(&self) {
+        if !self.table.is_empty() {
+            // Move up to the start of the table, and clear to the end of the screen.
+            print!("\x1b[{}A\x1b[J", self.table.len());
+        }
+    }
+
+    fn draw_table(&self) {
+        let style = if self.stale { Style::Warning } else { Style::Normal };
+        for line in &self.table {
+            println!("{}", self.paint(style, line));
+        }
+        let _ = io::stdout().flush();
     }
 }
 
-fn name_matches_str(name: &OwnedName, str_name: &str) -> bool {
-    &name.local_name == str_name
+/// Lay out a reading as a table, one value per line, in `units`.
+fn table(reading: &Reading, units: &Units) -> Vec<String> {
+    let mut rows = vec![];
+    if let Some(ref time) = reading.time {
+        rows.push(("time".to_string(), time.clone()));
+    }
+    if let Some(temperature) = reading.temperature {
+        rows.push(("temperature".to_string(), units.temperature(temperature)));
+    }
+    for (channel, watts) in &reading.watts {
+        rows.push((channel.clone(), format!("{} W", watts)));
+    }
+    if let Some(link) = reading.link {
+        rows.push(("link".to_string(), link.to_string()));
+    }
+    if reading.low_battery == Some(true) {
+        rows.push(("battery".to_string(), "low".to_string()));
+    }
+
+    let name_width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    let value_width = rows.iter().map(|(_, value)| value.chars().count()).max().unwrap_or(0);
+    rows.iter()
+        .map(|(name, value)| {
+            format!("{:<nw$}  {:>vw$}", name, value, nw = name_width, vw = value_width)
+        })
+        .collect()
 }
 
-// Parsers are responsible for parsing the *inside and end* of their tag,
-// having had their start parsed by their parent element. The exception to this is
-// the `Top` tag which parses its own start.
-fn run_parser<T: Read>(src: &mut EventReader<T>, parser: &Parser)
-    -> Result<HashMap<&'static str, String>, Box<Error>>
+/// Open the serial port, or if it's gone, the first other USB serial port that opens.
+///
+/// USB adapters can come back under a new name after being replugged, like `ttyUSB1` in
+/// place of `ttyUSB0`. `port` is the port opened last time, which is tried first.
+fn init_serial(config: &Config, port: &mut PathBuf, console: &mut Console)
+    -> Result<Source, Error>
 {
-    match *parser {
-        Top(tag, ref subparsers) => {
-            // Grab the start tag.
-            let start_tag = try!(src.next_tag());
-            match start_tag {
-                // If we have the correct start tag, all is well.
-                StartElement { ref name, .. } if name_matches_str(name, tag) => (),
-                // If we have another start tag, read to the end of it and bail.
-                StartElement { ref name, .. } => {
-                    src.read_to_tag_end(&name.local_name);
-                    return string_error(format!("Wrong start tag: {:?}", name));
-                }
-                // Anything else is bad.
-                e => return string_error(format!("Junk event: {:?}", e))
-            }
+    if let Some(address) = config.tcp_address() {
+        return open_tcp(config, address).map(Source::Tcp);
+    }
+    if is_fifo(&config.port) {
+        return open_fifo(&config.port, console).map(Source::Fifo);
+    }
 
-            // Parse the inside and end of the tag.
-            parse_tag(src, tag, subparsers)
+    let mut candidates = vec![config.port.clone()];
+    if !config.port.exists() {
+        candidates = scan_ports();
+        if let Some(i) = candidates.iter().position(|candidate| candidate == port) {
+            let last = candidates.remove(i);
+            candidates.insert(0, last);
         }
+        if candidates.is_empty() {
+            let message = format!("{}: no such device, and no other USB serial ports",
+                                  config.port.display());
+            return Err(serial::Error::new(serial::ErrorKind::NoDevice, message).into());
+        }
+    }
 
-        Tag(tag, ref subparsers) => parse_tag(src, tag, subparsers),
-
-        Contents(tag, key_name) => {
-            let mut result = HashMap::new();
-            match src.next_tag() {
-                Ok(Characters(tag_content)) => { result.insert(key_name, tag_content); },
-                _ => return string_error(format!("Tag contents not found for tag parser"))
+    let mut error = None;
+    for candidate in candidates {
+        match open_port(config, &candidate) {
+            Ok(opened) => {
+                if candidate != *port {
+                    console.message(Style::Warning, format!("Serial port is now {}, was {}",
+                                                            candidate.display(), port.display()));
+                    *port = candidate;
+                }
+                return Ok(Source::Serial(opened));
             }
-            src.read_to_tag_end(tag);
-            Ok(result)
+            Err(e) => error = Some(e)
         }
     }
+    Err(error.expect("there's always at least one candidate"))
 }
 
-fn parse_tag<T: Read>(src: &mut EventReader<T>, tag: &'static str, subparsers: &[Parser])
-    -> Result<HashMap<&'static str, String>, Box<Error>>
-{
-    let mut result = HashMap::new();
-    for subparser in subparsers {
-        debug!("Looking for a match for <{}>", subparser.tag_name());
-        // Loop through tokens until a match for this subparser is found.
-        loop {
-            match src.next_tag() {
-                Ok(StartElement { name: ref tag_name, .. }) => {
-                    // Tag matches sub-parser.
-                    if name_matches_str(tag_name, subparser.tag_name()) {
-                        debug!("Matched <{}>", subparser.tag_name());
-                        let subresult = try!(run_parser(src, subparser));
-                        result.extend(subresult);
-                        break;
-                    }
-                    // Otherwise, skip the tag.
-                    else {
-                        src.read_to_tag_end(&tag_name.local_name)
-                    }
+/// Connect to a serial port shared over the network.
+fn open_tcp(config: &Config, address: &str) -> Result<TcpStream, Error> {
+    let timeout = Duration::from_secs(config.timeout);
+    let connect = || -> io::Result<TcpStream> {
+        let mut error = io::Error::new(io::ErrorKind::NotFound, "no address for host");
+        for socket_address in address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&socket_address, timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(timeout))?;
+                    return Ok(stream);
                 }
-                _ => return string_error(format!("XML stream out of sync with parser"))
+                Err(e) => error = e
             }
         }
+        Err(error)
+    };
+    // Failing to connect isn't a timeout waiting for data, even if it timed out.
+    connect().map_err(|e| {
+        let message = format!("Can't connect to {}: {}", address, e);
+        serial::Error::new(serial::ErrorKind::Io(e.kind()), message).into()
+    })
+}
+
+fn is_fifo(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo())
+}
+
+/// Open a named pipe that another process writes the sensor's output into.
+///
+/// This waits for a writer, if there isn't one yet. Once the writer closes the pipe, the
+/// end of the stream reconnects like any other failure, which waits for the next one.
+fn open_fifo(path: &Path, console: &mut Console) -> Result<File, Error> {
+    console.message(Style::Normal, format!("Waiting for a writer on {}", path.display()));
+    File::open(path).map_err(|e| {
+        let message = format!("Can't open {}: {}", path.display(), e);
+        serial::Error::new(serial::ErrorKind::Io(e.kind()), message).into()
+    })
+}
+
+/// Where messages are read from.
+enum Source {
+    Serial(TTYPort),
+    /// A serial port shared over the network, by ser2net or the like.
+    Tcp(TcpStream),
+    /// A named pipe, written to by another process.
+    Fifo(File)
+}
+
+impl Read for Source {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Source::Serial(ref mut port) => port.read(buf),
+            Source::Tcp(ref mut stream) => stream.read(buf).map_err(|e| {
+                // A socket's read timeout shows up as WouldBlock on Unix.
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    io::Error::new(io::ErrorKind::TimedOut, e)
+                } else {
+                    e
+                }
+            }),
+            Source::Fifo(ref mut file) => file.read(buf)
+        }
     }
-    // Read to end of tag.
-    src.read_to_tag_end(tag);
-    Ok(result)
 }
 
-fn init_serial() -> Result<TTYPort, Box<Error>> {
+fn open_port(config: &Config, path: &Path) -> Result<TTYPort, Error> {
     let settings = PortSettings {
         baud_rate: Baud57600,
         ..PortSettings::default()
     };
-    let mut port = try!(TTYPort::open(Path::new("/dev/ttyUSB0")));
-    try!(port.configure(&settings));
-    try!(port.set_timeout(Duration::new(ONE_DAY, 0)));
+    let mut port = TTYPort::open(path)?;
+    port.configure(&settings)?;
+    port.set_timeout(Duration::from_secs(config.timeout))?;
     Ok(port)
 }
 
-fn main_with_result() -> Result<(), Box<Error>> {
-    let serial_input = try!(init_serial());
+/// The USB serial ports there are, in order.
+fn scan_ports() -> Vec<PathBuf> {
+    let mut ports: Vec<PathBuf> = fs::read_dir("/dev").into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name().and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("ttyUSB") || name.starts_with("ttyACM"))
+        })
+        .collect();
+    ports.sort();
+    ports
+}
+
+/// Run a backend on its own thread, behind its own queue, so that it can't hold up
+/// reading or the other backends. The queue is watched under the name `backend`.
+fn queued<S: Sink + Send + 'static>(sink: S, queue: &QueueConfig, backend: String,
+                                    queues: &mut Vec<(String, QueueStats)>)
+    -> QueuedSink
+{
+    let drain_timeout = Duration::from_secs(queue.drain_timeout);
+    let queued = QueuedSink::new(sink, queue.capacity, queue.overflow, drain_timeout);
+    queues.push((backend, queued.stats()));
+    queued
+}
+
+/// The backends, and their queues to watch. HTTP backends use `proxy`, if given, in place
+/// of the one from the environment, and the metrics show `parse_errors`.
+fn open_sinks(config: &Config, proxy: Option<&str>, parse_errors: &ParseErrors)
+    -> Result<(MultiSink, Vec<(String, QueueStats)>), Error>
+{
+    let mut sink = MultiSink::new();
+    let mut queues = vec![];
+    // Each server gets a queue of its own, so one being down doesn't hold up the others.
+    let festivus = &config.festivus;
+    let channels = festivus.fields.channels().map(|(_, channel)| channel);
+    for url in &festivus.urls {
+        sink.add(queued(FestivusSink::new(url, channels, festivus.missing),
+                        festivus.queue.as_ref().unwrap_or(&config.queue),
+                        format!("festivus {}", url), &mut queues));
+    }
+    if let Some(ref jsonl) = config.jsonl {
+        let flush_interval = Duration::from_secs(jsonl.flush_interval);
+        sink.add(queued(JsonlSink::open(&jsonl.path, jsonl.gzip, jsonl.rotation(), flush_interval)?,
+                        jsonl.queue.as_ref().unwrap_or(&config.queue), "jsonl".into(),
+                        &mut queues));
+    }
+    if let Some(ref archive) = config.archive {
+        let flush_interval = Duration::from_secs(archive.flush_interval);
+        sink.add(queued(ArchiveSink::open(&archive.path, archive.gzip, archive.rotation(),
+                                          flush_interval)?,
+                        archive.queue.as_ref().unwrap_or(&config.queue), "archive".into(),
+                        &mut queues));
+    }
+    if let Some(ref csv) = config.csv {
+        let flush_interval = Duration::from_secs(csv.flush_interval);
+        sink.add(queued(CsvSink::open(&csv.path, csv.gzip, csv.rotation(), flush_interval,
+                                      config.output_channels())?,
+                        csv.queue.as_ref().unwrap_or(&config.queue), "csv".into(),
+                        &mut queues));
+    }
+    if let Some(ref pushgateway) = config.pushgateway {
+        let interval = Duration::from_secs(pushgateway.interval);
+        let pushgateway_sink = PushgatewaySink::new(&pushgateway.url, &pushgateway.job, interval,
+                                                    &config.metric_labels, &config.user_agent,
+                                                    proxy)?;
+        sink.add(queued(pushgateway_sink, pushgateway.queue.as_ref().unwrap_or(&config.queue),
+                        "pushgateway".into(), &mut queues));
+    }
+    // Serving metrics only means storing the reading, so it doesn't need a queue.
+    if let Some(ref prometheus) = config.prometheus {
+        sink.add(PrometheusSink::serve(&prometheus.listen,
+                                       Duration::from_secs(prometheus.stale_after),
+                                       &config.metric_labels, queues.clone(),
+                                       parse_errors.clone())?);
+    }
+    Ok((sink, queues))
+}
+
+/// Counts since the last heartbeat, which summarises them on a line like
+/// `heartbeat readings=10 parse_errors=0 backend_errors=0 queued=0 dropped=0
+/// total_watts=345`.
+struct Heartbeat {
+    interval: Option<Duration>,
+    last: Instant,
+    readings: u64,
+    backend_errors: u64,
+    total_watts: Option<f64>,
+    /// The backends' queues, and how many readings they'd dropped at the last heartbeat.
+    queues: Vec<(String, QueueStats)>,
+    dropped: u64,
+    /// Every parse error, and how many there'd been at the last heartbeat.
+    parse_errors: ParseErrors,
+    last_parse_errors: u64
+}
 
-    let mut event_reader = EventReader::new(serial_input);
+impl Heartbeat {
+    /// A heartbeat every `interval` seconds, or never if it's 0.
+    fn new(interval: u64, queues: Vec<(String, QueueStats)>, parse_errors: ParseErrors)
+        -> Heartbeat
+    {
+        Heartbeat {
+            interval: if interval == 0 { None } else { Some(Duration::from_secs(interval)) },
+            last: Instant::now(),
+            readings: 0,
+            backend_errors: 0,
+            total_watts: None,
+            queues,
+            dropped: 0,
+            parse_errors,
+            last_parse_errors: 0
+        }
+    }
 
-    let parser =
-        Top("msg", vec![
-            Contents("time", "time"),
-            Contents("tmpr", "temperature"),
-            Tag("ch1", vec![Contents("watts", "total")]),
-            Tag("ch2", vec![Contents("watts", "hot_water")]),
-            Tag("ch3", vec![Contents("watts", "solar")])
-        ]);
+    fn reading(&mut self, reading: &Reading) {
+        self.readings += 1;
+        if let Some(&total) = reading.watts.get("total") {
+            self.total_watts = Some(total);
+        }
+    }
 
-    let client = Festivus::new("http://localhost:3000");
+    /// Show the heartbeat and start counting again, if it's time.
+    fn beat_if_due(&mut self, console: &mut Console) {
+        match self.interval {
+            Some(interval) if self.last.elapsed() >= interval => (),
+            _ => return
+        }
+        let parse_errors = self.parse_errors.total();
+        let mut line = format!("heartbeat readings={} parse_errors={} backend_errors={}",
+                               self.readings, parse_errors - self.last_parse_errors,
+                               self.backend_errors);
+        self.last_parse_errors = parse_errors;
+        // Readings waiting in every queue, and dropped from them since the last heartbeat.
+        if !self.queues.is_empty() {
+            let queued: usize = self.queues.iter().map(|(_, stats)| stats.depth()).sum();
+            let dropped: u64 = self.queues.iter().map(|(_, stats)| stats.dropped()).sum();
+            line += &format!(" queued={} dropped={}", queued, dropped - self.dropped);
+            self.dropped = dropped;
+        }
+        if let Some(total) = self.total_watts {
+            line += &format!(" total_watts={}", total);
+        }
+        console.message(Style::Normal, line);
 
-    loop {
-        let data = match run_parser(&mut event_reader, &parser) {
-            Ok(x) => x,
-            Err(e) => {
-                println!("Parse error: {}", e);
-                continue;
+        self.last = Instant::now();
+        self.readings = 0;
+        self.backend_errors = 0;
+    }
+}
+
+/// The summary of the current day, shown once the first reading of the next day arrives.
+struct Daily<'a> {
+    config: Option<&'a SummaryConfig>,
+    summary: Option<DaySummary>
+}
+
+impl<'a> Daily<'a> {
+    /// Summaries as the config says, if at all.
+    fn new(config: Option<&'a SummaryConfig>) -> Daily<'a> {
+        Daily { config, summary: None }
+    }
+
+    fn reading(&mut self, reading: &Reading, console: &mut Console) {
+        if self.config.is_none() {
+            return;
+        }
+        let date = summary::date_of(reading);
+        // The watts held across midnight are split between the two days.
+        let next_day = match self.summary {
+            Some(ref mut summary) if summary.date() != date => Some(summary.roll_over(reading)),
+            _ => None
+        };
+        if let Some(next_day) = next_day {
+            self.finish(console, true);
+            self.summary = Some(next_day);
+        }
+        self.summary.get_or_insert_with(|| DaySummary::new(date)).add(reading);
+    }
+
+    /// Show the summary so far, also writing it to the file if the day is over.
+    fn finish(&mut self, console: &mut Console, day_over: bool) {
+        let (config, summary) = match (self.config, self.summary.take()) {
+            (Some(config), Some(summary)) => (config, summary),
+            _ => return
+        };
+        let report = summary.report(config.price_per_kwh);
+        let line = report.line(&console.units).to_string();
+        console.message(Style::Normal, line);
+        if let (true, Some(path)) = (day_over, &config.path) {
+            if let Err(e) = report.append_to(path) {
+                console.message(Style::Warning, e);
             }
+        }
+    }
+}
+
+/// Read a message and convert it to a reading.
+fn read<T: Read>(src: &mut EventStream<T>, parser: &Parser, config: &Config,
+                 console: &mut Console, data: &mut HashMap<&'static str, String>)
+    -> Result<Reading, Error>
+{
+    let (reading, errors) = Reading::read(src, parser, config, data)?;
+    for e in errors {
+        console.message(Style::Warning, e);
+    }
+    Ok(reading)
+}
+
+/// The file that bytes from the serial port are saved to, with `--record`.
+struct Recorder {
+    path: PathBuf,
+    file: BufWriter<File>
+}
+
+impl Recorder {
+    fn create(path: &Path) -> Result<Recorder, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Can't record to {}: {}", path.display(), e))?;
+        Ok(Recorder { path: path.to_path_buf(), file: BufWriter::new(file) })
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.file.flush()
+            .map_err(|e| format!("Error recording to {}: {}", self.path.display(), e))
+    }
+}
+
+/// Reads from the serial port, saving everything read to the recorder, if there is one.
+struct Recording<'a, R> {
+    port: R,
+    recorder: Option<&'a mut Recorder>
+}
+
+impl<'a, R: Read> Read for Recording<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.port.read(buf)?;
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.file.write_all(&buf[..n]).map_err(|e| {
+                let message = format!("Error recording to {}: {}", recorder.path.display(), e);
+                io::Error::new(e.kind(), message)
+            })?;
+        }
+        Ok(n)
+    }
+}
+
+/// How many made-up messages the self-test reads.
+const SELF_TEST_MESSAGES: u32 = 3;
+
+/// Read made-up messages with every tag the parser looks for, and send them to no
+/// backends, checking that every value comes through. Returns whether they all did.
+fn self_test(config: &Config, parser: &Parser, console: &mut Console) -> bool {
+    let mut stream = String::new();
+    let mut expected = vec![];
+    for n in 0..SELF_TEST_MESSAGES {
+        let mut values = HashMap::new();
+        let mut channels = 0;
+        stream += &parser.example(&mut |key| {
+            let value = match key {
+                "time" => format!("12:00:{:02}", n * 6),
+                "temperature" => format!("2{}.5", n),
+                "low_battery" => "0".to_string(),
+                "link" => (n + 1).to_string(),
+                "firmware" => "CC128-v0.11".to_string(),
+                _ if config.text_keys.iter().any(|text_key| text_key == key) => {
+                    format!("{}-{}", key, n)
+                }
+                _ => {
+                    channels += 1;
+                    (100 * (n + 1) + channels).to_string()
+                }
+            };
+            values.insert(key, value.clone());
+            value
+        });
+        stream += "\r\n";
+        expected.push(values);
+    }
+
+    let mut src = EventStream::new(stream.as_bytes());
+    let mut sink = MultiSink::new();
+    let mut data = HashMap::new();
+    let mut passed = true;
+    for (n, expected) in expected.iter().enumerate() {
+        let result = Reading::read(&mut src, parser, config, &mut data)
+            .and_then(|(reading, errors)| {
+                console.reading(&reading);
+                // Every value is good, so none should have failed.
+                if let Some(e) = errors.into_iter().next() {
+                    return Err(e);
+                }
+                sink.send(&reading)
+            });
+        let problem = match result {
+            Err(e) => e.to_string(),
+            Ok(()) if data != *expected => format!("read {:?}, expected {:?}", data, expected),
+            Ok(()) => continue
         };
-        println!("{:?}", data);
+        console.message(Style::Error, format!("Message {}: {}", n + 1, problem));
+        passed = false;
+    }
 
-        let total = try!(data["total"].parse());
-        let hot_water = try!(data["hot_water"].parse());
-        let solar = try!(data["solar"].parse());
+    if passed {
+        console.message(Style::Normal,
+                        format!("Self-test passed, reading {} messages with channels {}",
+                                SELF_TEST_MESSAGES, config.output_channels().join(", ")));
+    } else {
+        console.message(Style::Error, "Self-test failed");
+    }
+    passed
+}
+
+/// Everything kept from one connection to the next.
+struct Session<'a> {
+    heartbeat: Heartbeat,
+    daily: Daily<'a>,
+    pipeline: Pipeline,
+    /// The serial port opened last.
+    port: PathBuf,
+    /// Connections in a row that failed before reading a message.
+    failures: u32,
+    recorder: Option<Recorder>
+}
+
+/// Read and send readings until `shutdown` is set.
+fn main_with_result(config: &Config, parser: &Parser, sink: &mut dyn Sink,
+                    console: &mut Console, session: &mut Session, shutdown: &AtomicBool)
+    -> Result<(), Error>
+{
+    let serial_input = init_serial(config, &mut session.port, console)?;
+    let Session {
+        ref mut heartbeat, ref mut daily, ref mut pipeline, ref mut failures, ref mut recorder,
+        ..
+    } = *session;
+
+    let mut event_stream = EventStream::new(Recording {
+        port: serial_input,
+        recorder: recorder.as_mut()
+    });
+    if config.archive.is_some() {
+        event_stream.keep_raw();
+    }
+    if config.passthrough {
+        event_stream.capture_unknown();
+    }
 
-        if let Err(e) = client.insert(total, hot_water, solar) {
-            println!("Error connecting to Festivus: {:?}", e);
+    // Re-used for every message to avoid allocating.
+    let mut data = HashMap::new();
+
+    while !shutdown.load(Ordering::SeqCst) {
+        console.logged();
+        heartbeat.beat_if_due(console);
+        let result = read(&mut event_stream, parser, config, console, &mut data)
+            .and_then(|mut reading| {
+                *failures = 0;
+                heartbeat.parse_errors.parsed();
+                let applied = pipeline.apply(&mut reading);
+                if let Some(firmware) = applied.firmware {
+                    console.message(Style::Normal, format!("Monitor firmware {}", firmware));
+                }
+                if let Some((sensor, time)) = applied.repeat {
+                    console.message(Style::Warning,
+                                    format!("Dropped a repeated reading from sensor {} at {}",
+                                            sensor, time));
+                    return Ok(());
+                }
+                console.reading(&reading);
+                match applied.exporting {
+                    Some(true) => console.message(Style::Normal, "Started exporting solar"),
+                    Some(false) => console.message(Style::Normal, "Stopped exporting solar"),
+                    None => ()
+                }
+                heartbeat.reading(&reading);
+                daily.reading(&reading, console);
+                sink.send(&reading)
+            });
+        match result {
+            Ok(()) => (),
+            // Skip to the next message.
+            Err(e @ Error::Parse(_)) | Err(e @ Error::Xml(_)) => {
+                console.message(Style::Error, format!("Parse error: {}", e));
+                // Every `max` in a row, so that a reconnect that doesn't help is tried
+                // again, rather than after every error from then on.
+                let in_a_row = heartbeat.parse_errors.error();
+                if config.parse_errors.max.is_some_and(|max| in_a_row % max == 0) {
+                    return Err(Error::Parse(format!("{} parse errors in a row", in_a_row)));
+                }
+            }
+            // The Envi sends every few seconds, so it may have gone away. Reconnect.
+            Err(e @ Error::Timeout) => {
+                console.stale(e);
+                return Ok(());
+            }
+            // The reading was dropped, or an earlier one was.
+            Err(e @ Error::Backend(_)) => {
+                heartbeat.backend_errors += 1;
+                console.message(Style::Warning, e);
+            }
+            // Anything else needs a reconnect, or is fatal.
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(())
+}
+
+/// How long `check` waits for the metrics.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Check a running instance's metrics, printing a one-line status like a Nagios check.
+/// Returns the exit code: 0 if it's read from the sensor within `max_age` seconds, 2 if
+/// it hasn't, or 3 if it can't tell.
+fn check(config: &Config, max_age: Option<u64>) -> i32 {
+    let prometheus = match config.prometheus {
+        Some(ref prometheus) => prometheus,
+        None => {
+            println!("UNKNOWN: there's no [prometheus] section to check the metrics of");
+            return 3;
+        }
+    };
+    let max_age = max_age.unwrap_or(prometheus.stale_after);
+    let metrics = match fetch_metrics(&prometheus.listen) {
+        Ok(metrics) => metrics,
+        Err(e) => {
+            println!("UNKNOWN: can't get metrics from {}: {}", prometheus.listen, e);
+            return 3;
+        }
+    };
+    let name = "household_power_last_reading_timestamp_seconds";
+    let last_reading = metrics.lines()
+        .filter(|line| {
+            line.strip_prefix(name).is_some_and(|rest| rest.starts_with([' ', '{']))
+        })
+        .find_map(|line| line.split_whitespace().last()?.parse::<f64>().ok());
+    let last_reading = match last_reading {
+        Some(last_reading) => last_reading,
+        None => {
+            println!("CRITICAL: no readings yet");
+            return 2;
         }
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+    let age = (now - last_reading).max(0.0);
+    if age <= max_age as f64 {
+        println!("OK: last reading {:.0}s ago", age);
+        0
+    } else {
+        println!("CRITICAL: last reading {:.0}s ago, more than {}s", age, max_age);
+        2
+    }
+}
+
+/// The body of `/metrics` from the instance listening on `listen`.
+fn fetch_metrics(listen: &str) -> io::Result<String> {
+    // It may be listening on every address, but can be reached on this machine's own.
+    let address = listen.replacen("0.0.0.0:", "127.0.0.1:", 1).replacen("[::]:", "[::1]:", 1);
+    let socket_address = address.to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for host"))?;
+    let mut stream = TcpStream::connect_timeout(&socket_address, CHECK_TIMEOUT)?;
+    stream.set_read_timeout(Some(CHECK_TIMEOUT))?;
+    write!(stream, "GET /metrics HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", address)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let status = head.split_whitespace().nth(1).unwrap_or("");
+    if status != "200" {
+        return Err(io::Error::other(format!("unexpected response {:?}",
+                                            head.lines().next().unwrap_or(""))));
     }
+    Ok(body.to_string())
 }
 
 fn main() {
-    loop {
-        if let Err(e) = main_with_result() {
+    let args = Args::parse();
+    if let Some(Command::Check { ref config, max_age }) = args.command {
+        let config = match config {
+            Some(path) => Config::load(path),
+            None => Ok(Config::default())
+        };
+        let config = config.unwrap_or_else(|e| {
+            println!("UNKNOWN: {}", e);
+            process::exit(3);
+        });
+        process::exit(check(&config, max_age));
+    }
+
+    // The config's printed as TOML alone, so that it can be saved as a config file.
+    if !args.print_config {
+        println!("household-power {}", VERSION);
+    }
+    let config = match args.config {
+        Some(ref path) => Config::load(path),
+        None => Ok(Config::default())
+    };
+    let config = config.unwrap_or_else(|e| {
+        println!("{}", e);
+        process::exit(1);
+    });
+    let parser = config.parser().unwrap_or_else(|e| {
+        println!("{}", e);
+        process::exit(1);
+    });
+
+    let toml = config.to_toml().unwrap_or_else(|e| {
+        println!("{}", e);
+        process::exit(1);
+    });
+    if args.print_config {
+        print!("{}", toml);
+        return;
+    }
+    // So that it's clear which settings are in effect, with the secrets masked.
+    print!("Config in effect:\n{}", toml);
+
+    if args.self_test {
+        let mut console = Console::new(Output::Lines, args.color, &config.units);
+        process::exit(if self_test(&config, &parser, &mut console) { 0 } else { 1 });
+    }
+
+    if log::set_logger(&LOGGED).is_ok() {
+        log::set_max_level(LevelFilter::Warn);
+    }
+    let parse_errors = ParseErrors::default();
+    let (mut sink, queues) = open_sinks(&config, args.proxy.as_deref(), &parse_errors)
+        .unwrap_or_else(|e| {
             println!("{}", e);
+            process::exit(1);
+        });
+    let heartbeat = Heartbeat::new(config.heartbeat, queues, parse_errors);
+
+    // Finish the current message on SIGINT or SIGTERM, so that files are written out
+    // properly. A second signal exits straight away.
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let handler_shutdown = shutdown.clone();
+    let handler = ctrlc::set_handler(move || {
+        if handler_shutdown.swap(true, Ordering::SeqCst) {
+            process::exit(130);
+        }
+        println!("Shutting down after the next message");
+    });
+    if let Err(e) = handler {
+        println!("Can't handle signals: {}", e);
+    }
+
+    // It's left to finish on its own, or be killed on exit.
+    if let Some(ref export) = config.export {
+        export::spawn(export, shutdown.clone());
+    }
+
+    let recorder = args.record.as_ref().map(|path| Recorder::create(path)).transpose();
+    let recorder = recorder.unwrap_or_else(|e| {
+        println!("{}", e);
+        process::exit(1);
+    });
+
+    let mut console = Console::new(args.output, args.color, &config.units);
+
+    // The dashboard has the terminal to itself, while readings are read on another thread.
+    #[cfg(feature = "tui")]
+    {
+        if console.output == Output::Tui {
+            let dashboard = console.dashboard.clone();
+            let worker_shutdown = shutdown.clone();
+            let worker = thread::spawn(move || {
+                let result = run(&config, &parser, &mut sink, heartbeat, &mut console, recorder,
+                                 &worker_shutdown);
+                // Take the dashboard down too, if reading gave up.
+                worker_shutdown.store(true, Ordering::SeqCst);
+                result
+            });
+            if let Err(e) = tui::run(&dashboard, &shutdown) {
+                println!("{}", e);
+            }
+            println!("Shutting down after the next message");
+            let result = worker.join();
+            LOGGED.print();
+            match result {
+                Ok(Ok(())) => return,
+                Ok(Err(e)) => println!("{}", e),
+                Err(_) => ()
+            }
+            process::exit(1);
+        }
+    }
+
+    let result = run(&config, &parser, &mut sink, heartbeat, &mut console, recorder, &shutdown);
+    // Let the queues drain first.
+    drop(sink);
+    LOGGED.print();
+    if result.is_err() {
+        process::exit(1);
+    }
+}
+
+/// Read and send readings until `shutdown` is set, reconnecting as needed.
+///
+/// Returns the last error if reconnecting fails too many times in a row, or there are too
+/// many parse errors in a row and the config says to exit. A config error is returned
+/// straight away.
+fn run(config: &Config, parser: &Parser, sink: &mut MultiSink, heartbeat: Heartbeat,
+       console: &mut Console, recorder: Option<Recorder>, shutdown: &AtomicBool)
+    -> Result<(), Error>
+{
+    let mut session = Session {
+        heartbeat,
+        daily: Daily::new(config.summary.as_ref()),
+        pipeline: Pipeline::new(config),
+        port: config.port.clone(),
+        failures: 0,
+        recorder
+    };
+    let mut result = Ok(());
+    while !shutdown.load(Ordering::SeqCst) {
+        let connection = main_with_result(config, parser, sink, console, &mut session, shutdown);
+        // Save what was recorded of the connection, in case the next one never ends.
+        if let Some(Err(e)) = session.recorder.as_mut().map(Recorder::flush) {
+            console.message(Style::Error, e);
+        }
+        match connection {
+            // Reconnecting won't help, but the sinks are still written out on the way.
+            Err(e @ Error::Config(_)) => {
+                console.message(Style::Error, &e);
+                result = Err(e);
+                break;
+            }
+            // Only returned after too many parse errors in a row.
+            Err(e @ Error::Parse(_)) if config.parse_errors.action == ParseErrorAction::Exit => {
+                console.message(Style::Error, format!("Giving up after {}", e));
+                result = Err(e);
+                break;
+            }
+            Err(e) => {
+                console.message(Style::Error, &e);
+                session.failures += 1;
+                let reconnect = &config.reconnect;
+                if reconnect.max_attempts.is_some_and(|max| session.failures > max) {
+                    console.message(Style::Error,
+                                    format!("Giving up after {} failures in a row",
+                                            session.failures));
+                    result = Err(e);
+                    break;
+                }
+                sleep_unless_shutdown(reconnect.delay_after(session.failures), shutdown);
+            }
+            Ok(()) => ()
         }
     }
+
+    session.daily.finish(console, false);
+    console.logged();
+
+    // Write out anything buffered. The queues are emptied, up to their timeouts, and
+    // compressed files are finished when the sink is dropped.
+    if let Err(e) = sink.flush() {
+        console.message(Style::Error, e);
+    }
+    result
+}
+
+/// Sleep for `duration`, waking early if `shutdown` is set.
+fn sleep_unless_shutdown(duration: Duration, shutdown: &AtomicBool) {
+    let deadline = Instant::now() + duration;
+    while !shutdown.load(Ordering::SeqCst) {
+        let now = Instant::now();
+        if now >= deadline {
+            return;
+        }
+        thread::sleep((deadline - now).min(Duration::from_millis(100)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn args() {
+        Args::command().debug_assert();
+    }
+
+    /// The help is part of the interface, like the config, so changes to it show up here.
+    #[test]
+    fn help() {
+        let help = Args::command().render_help().to_string();
+        // The dashboard is only listed as an output when it's built in.
+        let name = if cfg!(feature = "tui") { "help_tui" } else { "help" };
+        insta::assert_snapshot!(name, help);
+    }
 }
@@ -0,0 +1,1017 @@
+use std::io::{self, Read};
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+
+use xml::{EventReader, ParserConfig};
+use xml::reader::XmlEvent;
+use xml::reader::XmlEvent::*;
+use xml::attribute::OwnedAttribute;
+use xml::name::OwnedName;
+
+use crate::error::Error;
+use self::Parser::*;
+
+fn parse_error<T, S: Into<String>>(s: S) -> Result<T, Error> {
+    Err(Error::Parse(s.into()))
+}
+
+/// A description of the XML tags to extract values from.
+pub enum Parser {
+    /// The outermost tag of a message, and parsers for its children.
+    Top(&'static str, Vec<Parser>),
+    /// A nested tag, and parsers for its children.
+    Tag(&'static str, Vec<Parser>),
+    /// A tag whose text contents are stored under the given key.
+    Contents(&'static str, &'static str),
+    /// An attribute of the enclosing `Top` or `Tag`, stored under the given key.
+    Attribute(&'static str, &'static str),
+    /// A parser for a tag that may be missing, which isn't an error.
+    Optional(Box<Parser>)
+}
+
+/// A name for a parser, which only takes static names. Parsers are built once at startup, so
+/// leaking them is fine.
+pub(crate) fn leak(name: &str) -> &'static str {
+    Box::leak(name.to_string().into_boxed_str())
+}
+
+impl Parser {
+    /// The name of the tag parsed, which for an attribute matches no tag.
+    fn tag_name(&self) -> &'static str {
+        match *self {
+            Top(x, _) | Tag(x, _) | Contents(x, _) => x,
+            Attribute(..) => "",
+            Optional(ref parser) => parser.tag_name()
+        }
+    }
+
+    /// The attribute's name and key, if this parses an attribute.
+    fn attribute(&self) -> Option<(&'static str, &'static str)> {
+        match *self {
+            Attribute(name, key) => Some((name, key)),
+            Optional(ref parser) => parser.attribute(),
+            _ => None
+        }
+    }
+
+    /// A message this parser reads, with every tag and attribute it looks for, even the
+    /// optional ones. Each value is whatever `value` returns for its key.
+    pub fn example<F: FnMut(&'static str) -> String>(&self, value: &mut F) -> String {
+        let mut message = String::new();
+        self.write_example(&mut message, value);
+        message
+    }
+
+    fn write_example<F: FnMut(&'static str) -> String>(&self, out: &mut String, value: &mut F) {
+        match *self {
+            Top(tag, ref subparsers) | Tag(tag, ref subparsers) => {
+                out.push('<');
+                out.push_str(tag);
+                for (name, key) in subparsers.iter().filter_map(Parser::attribute) {
+                    out.push_str(&format!(" {}=\"{}\"", name, escape(&value(key))));
+                }
+                out.push('>');
+                for subparser in subparsers.iter().filter(|parser| parser.attribute().is_none()) {
+                    subparser.write_example(out, value);
+                }
+                out.push_str(&format!("</{}>", tag));
+            }
+            Contents(tag, key) => {
+                out.push_str(&format!("<{0}>{1}</{0}>", tag, escape(&value(key))));
+            }
+            Attribute(..) => (),
+            Optional(ref parser) => parser.write_example(out, value)
+        }
+    }
+
+    /// The key the tag's contents are stored under, if this parses a tag's contents.
+    fn contents_key(&self) -> Option<&'static str> {
+        match *self {
+            Contents(_, key) => Some(key),
+            Optional(ref parser) => parser.contents_key(),
+            _ => None
+        }
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('"', "&quot;")
+}
+
+/// The channel tags sent by a CurrentCost Envi, and the keys their readings are stored under.
+pub const ENVI_CHANNELS: [(&str, &str); 3] = [
+    ("ch1", "total"),
+    ("ch2", "hot_water"),
+    ("ch3", "solar")
+];
+
+/// The parser for live readings from a CurrentCost Envi.
+pub fn envi_parser() -> Parser {
+    envi_parser_for(|_| true)
+}
+
+/// The parser for live readings from a CurrentCost Envi, leaving out the channels whose
+/// keys `enabled` returns false for.
+pub fn envi_parser_for<F: Fn(&str) -> bool>(enabled: F) -> Parser {
+    let mut subparsers = vec![
+        Contents("time", "time"),
+        Contents("tmpr", "temperature"),
+        // Only sent by some sensors and firmware.
+        Optional(Box::new(Contents("lowbatt", "low_battery"))),
+        Optional(Box::new(Contents("link", "link"))),
+        Optional(Box::new(Contents("src", "firmware")))
+    ];
+    for &(tag, key) in &ENVI_CHANNELS {
+        if enabled(key) {
+            subparsers.push(Tag(tag, vec![Contents("watts", key)]));
+        }
+    }
+    Top("msg", subparsers)
+}
+
+/// A tag path like `msg/ch1/watts`, the key to store its contents under, and whether it
+/// may be missing.
+type Path = (Vec<&'static str>, &'static str, bool);
+
+/// Build a parser from a list of tag paths like `msg/ch1/watts`, the keys to store their
+/// contents under, and whether each may be missing from a message.
+///
+/// Every path must start with the same outermost tag.
+pub fn parser_for_paths(paths: &[(&'static str, &'static str, bool)]) -> Result<Parser, Error> {
+    let paths: Vec<Path> = paths.iter()
+        .map(|&(path, key, optional)| (path.split('/').collect(), key, optional))
+        .collect();
+    let top = match paths.first() {
+        Some((tags, _, _)) => tags[0],
+        None => return Err(Error::Config("No tag paths to parse".into()))
+    };
+    for (tags, _, _) in &paths {
+        let path = tags.join("/");
+        if tags.iter().any(|tag| tag.is_empty()) {
+            return Err(Error::Config(format!("{}: empty tag name", path)));
+        }
+        if tags[0] != top {
+            return Err(Error::Config(format!("{}: doesn't start with {}", path, top)));
+        }
+        if tags.len() < 2 {
+            return Err(Error::Config(format!("{}: needs a tag inside <{}>", path, top)));
+        }
+    }
+    Ok(Top(top, subparsers_for_paths(&paths, 1)?))
+}
+
+/// The subparsers for the tags at `depth` in `paths`, which share the tags before it.
+fn subparsers_for_paths(paths: &[Path], depth: usize) -> Result<Vec<Parser>, Error> {
+    // Keep the tags in the order they're first given.
+    let mut names: Vec<&'static str> = vec![];
+    for (tags, _, _) in paths {
+        if !names.contains(&tags[depth]) {
+            names.push(tags[depth]);
+        }
+    }
+    names.into_iter().map(|name| {
+        let group: Vec<Path> = paths.iter().filter(|path| path.0[depth] == name).cloned().collect();
+        let parser = match group[..] {
+            [(ref tags, key, _)] if tags.len() == depth + 1 => Contents(name, key),
+            _ if group.iter().any(|path| path.0.len() == depth + 1) => {
+                let path = group[0].0[..=depth].join("/");
+                return Err(Error::Config(format!("{}: given twice, or with tags inside it too",
+                                                 path)));
+            }
+            _ => Tag(name, subparsers_for_paths(&group, depth + 1)?)
+        };
+        // A tag may be missing if everything inside it may be.
+        if group.iter().all(|&(_, _, optional)| optional) {
+            Ok(Optional(Box::new(parser)))
+        } else {
+            Ok(parser)
+        }
+    }).collect()
+}
+
+/// The input to the XML reader, shared with the `EventStream` that owns the reader.
+///
+/// The XML reader gives up at its first error, so to carry on the stream takes back the
+/// input, skips to the start of the next message and hands it to a new reader.
+struct Input<T> {
+    source: T,
+    /// Bytes already taken from `source` that the reader should see first.
+    replay: Vec<u8>,
+    /// The last I/O error, which the XML reader only passes on as text.
+    last_error: Option<io::Error>,
+    /// Bytes the reader has read since the last message, if they're being kept.
+    raw: Option<Vec<u8>>,
+    /// The last few bytes the reader has read, up to `TAIL_LEN`.
+    tail: Vec<u8>
+}
+
+/// How many of the bytes read last are kept in `Input::tail`.
+const TAIL_LEN: usize = 64;
+
+impl<T: Read> Input<T> {
+    /// Keep bytes handed to the reader, as the raw message and the tail.
+    fn note_read(&mut self, bytes: &[u8]) {
+        if let Some(ref mut raw) = self.raw {
+            raw.extend_from_slice(bytes);
+        }
+        self.tail.extend_from_slice(bytes);
+        let excess = self.tail.len().saturating_sub(TAIL_LEN);
+        self.tail.drain(..excess);
+    }
+
+    /// Discard bytes up to the next `<tag`, and leave it to be replayed.
+    ///
+    /// The reader may have failed partway into it, as on the second `<` of `<wat<msg>`, so
+    /// the match picks up from the end of what the reader read.
+    fn skip_to_start_tag(&mut self, tag: &str) -> Result<(), Error> {
+        let start = format!("<{}", tag).into_bytes();
+        let mut matched = (1..=start.len()).rev()
+            .find(|&len| self.tail.ends_with(&start[..len]))
+            .unwrap_or(0);
+        self.tail.clear();
+        let mut byte = [0];
+        loop {
+            match self.source.read(&mut byte) {
+                Ok(0) => return Err(end_of_stream()),
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into())
+            }
+            // The name must end here, so that `<msgs>` isn't taken for `<msg>`.
+            if matched == start.len() {
+                if ends_name(byte[0]) {
+                    self.replay = start;
+                    self.replay.push(byte[0]);
+                    return Ok(());
+                }
+                matched = 0;
+            }
+            if byte[0] == start[matched] {
+                matched += 1;
+            } else if byte[0] == b'<' {
+                matched = 1;
+            } else {
+                matched = 0;
+            }
+        }
+    }
+}
+
+fn ends_name(byte: u8) -> bool {
+    byte == b'>' || byte == b'/' || (byte as char).is_whitespace()
+}
+
+/// Where the last `<tag` starts in `bytes`.
+fn rfind_start_tag(bytes: &[u8], tag: &str) -> Option<usize> {
+    let start = format!("<{}", tag).into_bytes();
+    bytes.windows(start.len() + 1)
+        .rposition(|window| window[..start.len()] == start[..] && ends_name(window[start.len()]))
+}
+
+struct SharedInput<T>(Rc<RefCell<Input<T>>>);
+
+impl<T: Read> Read for SharedInput<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut input = self.0.borrow_mut();
+        if !input.replay.is_empty() {
+            let n = cmp::min(buf.len(), input.replay.len());
+            buf[..n].copy_from_slice(&input.replay[..n]);
+            input.replay.drain(..n);
+            input.note_read(&buf[..n]);
+            return Ok(n);
+        }
+        let result = input.source.read(buf);
+        match result {
+            Ok(n) => input.note_read(&buf[..n]),
+            Err(ref e) => input.last_error = Some(io::Error::new(e.kind(), e.to_string()))
+        }
+        result
+    }
+}
+
+/// A reader for the shared input. CDATA sections are read as text, so that
+/// `<watts><![CDATA[345]]></watts>` has the same contents as `<watts>345</watts>`, and
+/// text is trimmed, so that indented XML has the same contents too.
+fn new_reader<T: Read>(input: &Rc<RefCell<Input<T>>>) -> EventReader<SharedInput<T>> {
+    ParserConfig::new()
+        .cdata_to_characters(true)
+        .trim_whitespace(true)
+        .create_reader(SharedInput(input.clone()))
+}
+
+fn end_of_stream() -> Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "End of stream").into()
+}
+
+/// A stream of XML events with room to push back a single event.
+///
+/// This lets a parser that has read one event too far (the start of the next message,
+/// say) leave it in place for the next parser to consume.
+pub struct EventStream<T: Read> {
+    input: Rc<RefCell<Input<T>>>,
+    reader: EventReader<SharedInput<T>>,
+    pushed_back: Option<XmlEvent>,
+    /// Whether the reader has stopped, and needs replacing before reading more.
+    failed: bool,
+    /// The raw bytes of the last message parsed, if they're being kept.
+    raw_message: Option<Vec<u8>>,
+    /// How many times each tag no parser wanted has been skipped, by `parent/tag`.
+    skipped: HashMap<String, u64>,
+    /// The contents of the last message's tags that no parser wanted, by path, if they're
+    /// being kept.
+    captured: Option<BTreeMap<String, String>>,
+    /// The tags being parsed, from the outermost in.
+    open_tags: Vec<&'static str>,
+    /// Whether the last message read to its end had a `<hist>` tag that no parser wanted.
+    history: bool
+}
+
+impl<T: Read> EventStream<T> {
+    pub fn new(source: T) -> EventStream<T> {
+        let input = Rc::new(RefCell::new(Input {
+            source,
+            replay: vec![],
+            last_error: None,
+            raw: None,
+            tail: vec![]
+        }));
+        EventStream {
+            reader: new_reader(&input),
+            input,
+            pushed_back: None,
+            failed: false,
+            raw_message: None,
+            skipped: HashMap::new(),
+            captured: None,
+            open_tags: vec![],
+            history: false
+        }
+    }
+
+    /// Keep the contents of every tag without tags of its own that no parser wants, rather
+    /// than skipping them, to be returned by `captured`.
+    pub fn capture_unknown(&mut self) {
+        self.captured = Some(BTreeMap::new());
+    }
+
+    /// The contents of the last message's tags that no parser wanted, by their path like
+    /// `msg/ch4/watts`, if `capture_unknown` has been called.
+    pub fn captured(&self) -> Option<&BTreeMap<String, String>> {
+        self.captured.as_ref()
+    }
+
+    /// Keep the raw bytes of each message, to be returned by `raw_message`.
+    pub fn keep_raw(&mut self) {
+        self.input.borrow_mut().raw = Some(vec![]);
+    }
+
+    /// The raw bytes of the last message parsed successfully, from the start of its
+    /// outermost tag to the end, if `keep_raw` has been called.
+    pub fn raw_message(&self) -> Option<&[u8]> {
+        self.raw_message.as_ref().map(|raw| &raw[..])
+    }
+
+    /// Split the kept raw bytes after a message, keeping the message if it parsed.
+    ///
+    /// Anything from the last `<top` on is kept for the next message if this one failed,
+    /// as that's where the reader picks up from, whether from a pushed back start tag or
+    /// after recovering.
+    fn end_raw_message(&mut self, top: &str, parsed: bool) {
+        let mut input = self.input.borrow_mut();
+        let raw = match input.raw {
+            Some(ref mut raw) => raw,
+            None => return
+        };
+        let start = rfind_start_tag(raw, top);
+        if parsed {
+            let message = raw.split_off(start.unwrap_or(0));
+            raw.clear();
+            self.raw_message = Some(message);
+        } else {
+            match start {
+                Some(start) => { raw.drain(..start); }
+                None => raw.clear()
+            }
+            self.raw_message = None;
+        }
+    }
+
+    /// How many times each tag that no parser wanted has been seen, by `parent/tag`.
+    ///
+    /// A tag like `msg/ch4` here means there's data the parser isn't configured for.
+    pub fn skipped_tags(&self) -> &HashMap<String, u64> {
+        &self.skipped
+    }
+
+    /// Count a tag that no parser wanted, logging it the 1st, 2nd, 4th, 8th... time.
+    fn note_skipped(&mut self, parent: &str, tag: &str) {
+        let path = format!("{}/{}", parent, tag);
+        let count = self.skipped.entry(path).or_insert(0);
+        *count += 1;
+        if count.is_power_of_two() {
+            info!("Skipped unexpected <{}> in <{}> ({} times so far)", tag, parent, count);
+        }
+    }
+
+    /// Read the inside and end of a tag that no parser wants, keeping the contents of it
+    /// and every tag inside it that has no tags of its own.
+    ///
+    /// This keeps a stack of the tags inside rather than recursing, as there's no bound on
+    /// how deeply they're nested.
+    fn capture_tag(&mut self, top: &str, tag: &str) -> Result<(), Error> {
+        let mut path = self.open_tags.join("/");
+        path.push('/');
+        path.push_str(tag);
+        // Each open tag's path, its contents so far, and whether it has no tags inside.
+        let mut open = vec![(path, String::new(), true)];
+        loop {
+            let next_tag = self.next_tag()?;
+            let new_message = match next_tag {
+                StartElement { ref name, .. } => name_matches_str(name, top),
+                _ => false
+            };
+            if new_message {
+                self.push_back(next_tag);
+                return parse_error(format!("Message truncated by a new <{}>", top));
+            }
+            match next_tag {
+                Characters(text) => {
+                    if let Some(innermost) = open.last_mut() {
+                        innermost.1.push_str(&text);
+                    }
+                }
+                StartElement { name, .. } => {
+                    let innermost = open.last_mut().expect("the captured tag is still open");
+                    innermost.2 = false;
+                    let path = format!("{}/{}", innermost.0, name.local_name);
+                    open.push((path, String::new(), true));
+                }
+                // The reader checks that end tags match their start tags.
+                EndElement { .. } => {
+                    if let Some((path, contents, true)) = open.pop() {
+                        if let Some(ref mut captured) = self.captured {
+                            captured.insert(path, contents);
+                        }
+                    }
+                    if open.is_empty() {
+                        return Ok(());
+                    }
+                }
+                _ => ()
+            }
+        }
+    }
+
+    /// If the reader has stopped after an error, skip to the next `<tag` and start a new
+    /// reader there.
+    fn recover(&mut self, tag: &str) -> Result<(), Error> {
+        if !self.failed {
+            return Ok(());
+        }
+        self.input.borrow_mut().skip_to_start_tag(tag)?;
+        self.reader = new_reader(&self.input);
+        self.pushed_back = None;
+        self.failed = false;
+        info!("Restarted reading at the next <{}>", tag);
+        Ok(())
+    }
+
+    /// Next important tag.
+    ///
+    /// The end of the document is returned as an error, as nothing more can be read.
+    fn next_tag(&mut self) -> Result<XmlEvent, Error> {
+        if let Some(tag) = self.pushed_back.take() {
+            return Ok(tag);
+        }
+        // Loop rather than recurse past ignored tags, as there's no bound on how many
+        // there are in a row.
+        loop {
+            let tag = match self.reader.next() {
+                Ok(tag) => tag,
+                Err(e) => {
+                    self.failed = true;
+                    // Report failures to read the input as such, not as bad XML.
+                    return Err(match self.input.borrow_mut().last_error.take() {
+                        Some(io_error) => io_error.into(),
+                        None => e.into()
+                    });
+                }
+            };
+            match tag {
+                // Ignored tag types.
+                StartDocument { .. } |
+                ProcessingInstruction { .. } |
+                Comment(..) |
+                Whitespace(..) => continue,
+                // The reader takes a failure to read between tags for the end, too.
+                EndDocument => {
+                    self.failed = true;
+                    return Err(match self.input.borrow_mut().last_error.take() {
+                        Some(io_error) => io_error.into(),
+                        None => end_of_stream()
+                    });
+                }
+                // Anything else (not ignored).
+                x => {
+                    info!("Read tag: {:?}", x);
+                    return Ok(x);
+                }
+            }
+        }
+    }
+
+    /// Return a tag to the stream, to be yielded by the next call to `next_tag`.
+    fn push_back(&mut self, tag: XmlEvent) {
+        debug_assert!(self.pushed_back.is_none());
+        self.pushed_back = Some(tag);
+    }
+
+    /// Consume tags until the given end tag is reached.
+    ///
+    /// A new `top` tag starting first means the message was cut short, and it's left for
+    /// the next message, as in `parse_tag`. Errors in the underlying stream are returned
+    /// rather than skipped, as the reader keeps returning the same error once it has
+    /// stopped.
+    fn read_to_tag_end(&mut self, top: &str, tag: &str) -> Result<(), Error> {
+        loop {
+            let next_tag = self.next_tag()?;
+            let new_message = match next_tag {
+                StartElement { ref name, .. } => name_matches_str(name, top),
+                _ => false
+            };
+            if new_message {
+                self.push_back(next_tag);
+                return parse_error(format!("Message truncated by a new <{}>", top));
+            }
+            if let EndElement { ref name, .. } = next_tag {
+                if name_matches_str(name, tag) {
+                    info!("Closed </{}>", tag);
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+// All tag name comparisons go through here, and borrow the name owned by the event
+// rather than copying it. The expected names are `&'static str`s from the parser tree.
+fn name_matches_str(name: &OwnedName, str_name: &str) -> bool {
+    name.local_name == str_name
+}
+
+// Parsers are responsible for parsing the *inside and end* of their tag,
+// having had their start parsed by their parent element. The exception to this is
+// the `Top` tag which parses its own start.
+
+/// Parse the next message from the stream, returning a map from keys to tag contents.
+pub fn run_parser<T: Read>(src: &mut EventStream<T>, parser: &Parser)
+    -> Result<HashMap<&'static str, String>, Error>
+{
+    let mut result = HashMap::new();
+    run_parser_into(src, parser, &mut result)?;
+    Ok(result)
+}
+
+/// Parse the next message from the stream into an existing map, which is cleared first.
+///
+/// Reusing the same map for every message saves allocating a new one each time.
+///
+/// Messages of history, which the Envi sends between live readings with a `<hist>` tag in
+/// place of the live ones, are skipped, unless the parser reads `<hist>` itself.
+pub fn run_parser_into<T: Read>(src: &mut EventStream<T>, parser: &Parser,
+                                result: &mut HashMap<&'static str, String>)
+    -> Result<(), Error>
+{
+    loop {
+        result.clear();
+        src.open_tags.clear();
+        src.history = false;
+        if let Some(ref mut captured) = src.captured {
+            captured.clear();
+        }
+        let parsed = run_nested_parser(src, parser.tag_name(), parser, &[], result);
+        src.end_raw_message(parser.tag_name(), parsed.is_ok());
+        match parsed {
+            Err(Error::Parse(_)) if src.history => debug!("Skipped a message of history"),
+            parsed => return parsed
+        }
+    }
+}
+
+// `top` is the tag name of the outermost parser. Seeing it start again before we're done
+// means the current message was cut short, and the new message is left for the next parse.
+// `attributes` are those of the tag's start, which has already been read, except for `Top`.
+fn run_nested_parser<T: Read>(src: &mut EventStream<T>, top: &'static str, parser: &Parser,
+                              attributes: &[OwnedAttribute],
+                              result: &mut HashMap<&'static str, String>)
+    -> Result<(), Error>
+{
+    match *parser {
+        Top(tag, ref subparsers) => {
+            // Pick up from the next message if the last one broke the reader.
+            src.recover(tag)?;
+
+            // Grab the start tag.
+            let start_tag = src.next_tag()?;
+            let attributes = match start_tag {
+                // If we have the correct start tag, all is well.
+                StartElement { ref name, attributes, .. } if name_matches_str(name, tag) => {
+                    attributes
+                }
+                // If we have another start tag, read to the end of it and bail.
+                StartElement { ref name, .. } => {
+                    src.read_to_tag_end(top, &name.local_name)?;
+                    return parse_error(format!("Wrong start tag: {:?}", name));
+                }
+                // Anything else is bad.
+                e => return parse_error(format!("Junk event: {:?}", e))
+            };
+
+            // Parse the inside and end of the tag.
+            parse_tag(src, top, tag, subparsers, &attributes, result)
+        }
+
+        Tag(tag, ref subparsers) => parse_tag(src, top, tag, subparsers, attributes, result),
+
+        Contents(tag, key_name) => {
+            // An empty tag, like `<watts/>`, leaves the key out, and whether that's an
+            // error is up to the enclosing tag's parser.
+            match read_contents(src, top, tag)? {
+                Some(tag_content) => { result.insert(key_name, tag_content); }
+                None => info!("Empty <{}>", tag)
+            }
+            Ok(())
+        }
+
+        // Attributes are read by the enclosing tag's parser, as it starts.
+        Attribute(..) => Ok(()),
+
+        Optional(ref parser) => run_nested_parser(src, top, parser, attributes, result)
+    }
+}
+
+fn parse_tag<T: Read>(src: &mut EventStream<T>, top: &'static str, tag: &'static str,
+                      subparsers: &[Parser], attributes: &[OwnedAttribute],
+                      result: &mut HashMap<&'static str, String>)
+    -> Result<(), Error>
+{
+    // Children can come in any order, and each is parsed by the first subparser for its
+    // name that hasn't been used yet.
+    let mut matched = vec![false; subparsers.len()];
+    let mut history = false;
+    src.open_tags.push(tag);
+
+    for (i, subparser) in subparsers.iter().enumerate() {
+        if let Some((name, key)) = subparser.attribute() {
+            let attribute = attributes.iter().find(|attribute| {
+                name_matches_str(&attribute.name, name)
+            });
+            if let Some(attribute) = attribute {
+                result.insert(key, attribute.value.clone());
+                matched[i] = true;
+            }
+        }
+    }
+
+    loop {
+        let next_tag = src.next_tag()?;
+
+        // A new message has started, so this one was truncated. Leave the new message
+        // for the next call to `run_parser`, rather than skipping over it.
+        let new_message = match next_tag {
+            StartElement { ref name, .. } => name_matches_str(name, top),
+            _ => false
+        };
+        if new_message {
+            src.push_back(next_tag);
+            return parse_error(format!("Message truncated by a new <{}>", top));
+        }
+
+        match next_tag {
+            StartElement { name: ref tag_name, ref attributes, .. } => {
+                let subparser = subparsers.iter().enumerate().find(|&(i, subparser)| {
+                    !matched[i] && name_matches_str(tag_name, subparser.tag_name())
+                });
+                match subparser {
+                    Some((i, subparser)) => {
+                        debug!("Matched <{}>", subparser.tag_name());
+                        run_nested_parser(src, top, subparser, attributes, result)?;
+                        // An empty tag counts as missing, so a later one can still fill it.
+                        matched[i] = subparser.contents_key()
+                            .is_none_or(|key| result.contains_key(key));
+                    }
+                    // Keep or skip tags that no subparser wants.
+                    None => {
+                        history |= tag == top && name_matches_str(tag_name, "hist");
+                        if src.captured.is_some() {
+                            src.capture_tag(top, &tag_name.local_name)?;
+                        } else {
+                            src.note_skipped(tag, &tag_name.local_name);
+                            src.read_to_tag_end(top, &tag_name.local_name)?;
+                        }
+                    }
+                }
+            }
+            EndElement { ref name, .. } if name_matches_str(name, tag) => {
+                // Only a whole message counts as history, not one cut short.
+                src.history = history;
+                break;
+            }
+            _ => return parse_error("XML stream out of sync with parser")
+        }
+    }
+
+    // Everything not optional must have been found.
+    for (subparser, &matched) in subparsers.iter().zip(&matched) {
+        match *subparser {
+            Optional(_) => (),
+            Attribute(name, _) if !matched => {
+                return parse_error(format!("Missing attribute {} on <{}>", name, tag));
+            }
+            _ if !matched => {
+                return parse_error(format!("Missing <{}> in <{}>", subparser.tag_name(), tag));
+            }
+            _ => ()
+        }
+    }
+    src.open_tags.pop();
+    info!("Closed </{}>", tag);
+    Ok(())
+}
+
+/// Read the inside and end of a tag that should hold only text, returning the text, or
+/// `None` if the tag is empty.
+fn read_contents<T: Read>(src: &mut EventStream<T>, top: &str, tag: &str)
+    -> Result<Option<String>, Error>
+{
+    let next_tag = src.next_tag()?;
+    let new_message = match next_tag {
+        StartElement { ref name, .. } => name_matches_str(name, top),
+        _ => false
+    };
+    if new_message {
+        src.push_back(next_tag);
+        return parse_error(format!("Message truncated by a new <{}>", top));
+    }
+    match next_tag {
+        Characters(contents) => {
+            src.read_to_tag_end(top, tag)?;
+            Ok(Some(contents))
+        }
+        // Whitespace has been skipped, so this is `<tag/>` or `<tag></tag>`.
+        EndElement { ref name, .. } if name_matches_str(name, tag) => Ok(None),
+        // Leave the stream at the end of the tag, as reading its contents would.
+        StartElement { ref name, .. } => {
+            src.read_to_tag_end(top, &name.local_name)?;
+            src.read_to_tag_end(top, tag)?;
+            parse_error(format!("Unexpected <{}> in <{}>", name.local_name, tag))
+        }
+        e => parse_error(format!("Tag contents not found for <{}>: {:?}", tag, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &str =
+        "<msg><time>13:02:45</time><tmpr>18.9</tmpr><ch1><watts>00350</watts></ch1>\
+         <ch2><watts>02150</watts></ch2><ch3><watts>00001</watts></ch3></msg>\r\n";
+
+    /// Parse the first `count` messages in `input` with the Envi parser.
+    ///
+    /// Not reading on to the end matters after a message is cut short, as its tags are
+    /// never closed, so the reader fails at the end of the input.
+    fn parse(input: &str, count: usize) -> Vec<Result<HashMap<&'static str, String>, Error>> {
+        let parser = envi_parser();
+        let mut src = EventStream::new(input.as_bytes());
+        (0..count).map(|_| run_parser(&mut src, &parser)).collect()
+    }
+
+    fn fields(values: &[(&'static str, &str)]) -> HashMap<&'static str, String> {
+        values.iter().map(|&(key, value)| (key, value.to_string())).collect()
+    }
+
+    fn message_fields() -> HashMap<&'static str, String> {
+        fields(&[("time", "13:02:45"), ("temperature", "18.9"), ("total", "00350"),
+                 ("hot_water", "02150"), ("solar", "00001")])
+    }
+
+    #[test]
+    fn concatenated_messages() {
+        for result in parse(&MESSAGE.repeat(2), 2) {
+            assert_eq!(result.unwrap(), message_fields());
+        }
+    }
+
+    #[test]
+    fn message_cut_short_in_text() {
+        let input = format!("<msg><time>13:02:39</time><tmpr>1</tmpr><ch1><watts>34{}", MESSAGE);
+        let results = parse(&input, 2);
+        assert!(matches!(results[0], Err(Error::Parse(_))));
+        assert_eq!(*results[1].as_ref().unwrap(), message_fields());
+    }
+
+    #[test]
+    fn message_cut_short_in_skipped_tag() {
+        let input = format!("<msg><time>13:02:39</time><sensor>1{}", MESSAGE);
+        let results = parse(&input, 2);
+        assert!(matches!(results[0], Err(Error::Parse(_))));
+        assert_eq!(*results[1].as_ref().unwrap(), message_fields());
+    }
+
+    #[test]
+    fn bad_xml_then_good_message() {
+        let input = format!("<msg><time>13:02:39</time><ch1><wat{}{}", MESSAGE, MESSAGE);
+        let results = parse(&input, 3);
+        assert!(matches!(results[0], Err(Error::Xml(_))));
+        assert_eq!(*results[1].as_ref().unwrap(), message_fields());
+        assert_eq!(*results[2].as_ref().unwrap(), message_fields());
+    }
+
+    #[test]
+    fn bad_xml_between_tags_then_good_message() {
+        let input = format!("<msg><time>13:02:39</time><<{}", MESSAGE);
+        let results = parse(&input, 2);
+        assert!(matches!(results[0], Err(Error::Xml(_))));
+        assert_eq!(*results[1].as_ref().unwrap(), message_fields());
+    }
+
+    /// The values in every fixture of the Envi's live messages.
+    fn fixture_fields() -> HashMap<&'static str, String> {
+        fields(&[("firmware", "CC128-v0.11"), ("time", "13:02:39"), ("temperature", "18.7"),
+                 ("total", "00345"), ("hot_water", "02151"), ("solar", "00000")])
+    }
+
+    #[test]
+    fn empty_optional_tags() {
+        let input = include_str!("../tests/fixtures/live_empty_tags.xml");
+        assert_eq!(parse(input, 1).remove(0).unwrap(), fixture_fields());
+    }
+
+    #[test]
+    fn cdata() {
+        let input = include_str!("../tests/fixtures/live_cdata.xml");
+        assert_eq!(parse(input, 1).remove(0).unwrap(), fixture_fields());
+    }
+
+    #[test]
+    fn indented() {
+        let input = include_str!("../tests/fixtures/live_indented.xml");
+        let results = parse(&input.repeat(2), 2);
+        for result in results {
+            assert_eq!(result.unwrap(), fixture_fields());
+        }
+    }
+
+    #[test]
+    fn well_formed_message() {
+        let mut src = EventStream::new(&include_bytes!("../tests/fixtures/live.xml")[..]);
+        assert_eq!(run_parser(&mut src, &envi_parser()).unwrap(), fixture_fields());
+    }
+
+    #[test]
+    fn extra_channel() {
+        let input: &[u8] =
+            b"<msg><src>CC128-v0.11</src><time>13:02:39</time><tmpr>18.7</tmpr>\
+              <ch1><watts>00345</watts></ch1><ch2><watts>02151</watts></ch2>\
+              <ch3><watts>00000</watts></ch3><ch4><watts>00123</watts></ch4></msg>\r\n";
+        let mut src = EventStream::new(input);
+        assert_eq!(run_parser(&mut src, &envi_parser()).unwrap(), fixture_fields());
+        assert_eq!(src.skipped_tags().get("msg/ch4"), Some(&1));
+    }
+
+    #[test]
+    fn missing_channel() {
+        let input: &[u8] =
+            b"<msg><src>CC128-v0.11</src><time>13:02:39</time><tmpr>18.7</tmpr>\
+              <ch1><watts>00345</watts></ch1><ch3><watts>00000</watts></ch3></msg>\r\n";
+        let mut src = EventStream::new(input);
+        match run_parser(&mut src, &envi_parser()) {
+            Err(Error::Parse(e)) => assert_eq!(e, "Missing <ch2> in <msg>"),
+            result => panic!("expected a parse error, got {:?}", result)
+        }
+    }
+
+    /// The shape of a generated parser tree, for `parser_trees_round_trip`. Each node's
+    /// `id` is its key, and picks whether it's in a message, and its value.
+    #[derive(Clone, Debug)]
+    struct Node {
+        shape: Shape,
+        optional: bool,
+        id: usize
+    }
+
+    #[derive(Clone, Debug)]
+    enum Shape {
+        Contents,
+        Attribute,
+        Tag(Vec<Node>)
+    }
+
+    fn node() -> impl proptest::strategy::Strategy<Value = Node> {
+        use proptest::prelude::*;
+        let leaf = (prop_oneof![Just(Shape::Contents), Just(Shape::Attribute)], any::<bool>())
+            .prop_map(|(shape, optional)| Node { shape, optional, id: 0 });
+        leaf.prop_recursive(4, 32, 4, |inner| {
+            (prop::collection::vec(inner, 0..4), any::<bool>())
+                .prop_map(|(children, optional)| {
+                    Node { shape: Shape::Tag(children), optional, id: 0 }
+                })
+        })
+    }
+
+    fn number(nodes: &mut [Node], next: &mut usize) {
+        for node in nodes {
+            node.id = *next;
+            *next += 1;
+            if let Shape::Tag(ref mut children) = node.shape {
+                number(children, next);
+            }
+        }
+    }
+
+    /// Siblings are named by their position, `t0` for tags and `a0` for attributes.
+    fn subparsers(nodes: &[Node]) -> Vec<Parser> {
+        nodes.iter().enumerate().map(|(i, node)| {
+            let key = leak(&format!("k{}", node.id));
+            let parser = match node.shape {
+                Shape::Contents => Contents(leak(&format!("t{}", i)), key),
+                Shape::Attribute => Attribute(leak(&format!("a{}", i)), key),
+                Shape::Tag(ref children) => Tag(leak(&format!("t{}", i)), subparsers(children))
+            };
+            if node.optional { Optional(Box::new(parser)) } else { parser }
+        }).collect()
+    }
+
+    /// A message for the tree, leaving out the optional nodes `present` says to, with the
+    /// children in reverse if `reverse` is set. The values go in `expected`, by key.
+    fn message(tag: &str, nodes: &[Node], present: &[bool], values: &[String], reverse: bool,
+               expected: &mut HashMap<String, String>)
+        -> String
+    {
+        let mut attributes = String::new();
+        let mut children = vec![];
+        for (i, node) in nodes.iter().enumerate() {
+            if node.optional && !present[node.id % present.len()] {
+                continue;
+            }
+            let value = &values[node.id % values.len()];
+            match node.shape {
+                Shape::Contents => {
+                    children.push(format!("<t{0}>{1}</t{0}>", i, value));
+                    expected.insert(format!("k{}", node.id), value.clone());
+                }
+                Shape::Attribute => {
+                    attributes += &format!(" a{}=\"{}\"", i, value);
+                    expected.insert(format!("k{}", node.id), value.clone());
+                }
+                Shape::Tag(ref inner) => {
+                    let name = format!("t{}", i);
+                    children.push(message(&name, inner, present, values, reverse, expected));
+                }
+            }
+        }
+        if reverse {
+            children.reverse();
+        }
+        format!("<{0}{1}>{2}</{0}>", tag, attributes, children.concat())
+    }
+
+    proptest::proptest! {
+        /// Messages for a random tree of tags, attributes and optional parts, a few in a
+        /// row so that a desync shows up in the next message, parse to exactly the values
+        /// in them.
+        #[test]
+        fn parser_trees_round_trip(
+            mut nodes in proptest::collection::vec(node(), 1..5),
+            messages in proptest::collection::vec(
+                (proptest::collection::vec(proptest::bool::ANY, 16),
+                 proptest::collection::vec("[a-z0-9]{1,8}", 16),
+                 proptest::bool::ANY),
+                1..4)
+        ) {
+            number(&mut nodes, &mut 0);
+            let parser = Top("msg", subparsers(&nodes));
+            let mut input = String::new();
+            let mut expected = vec![];
+            for (present, values, reverse) in &messages {
+                let mut values_by_key = HashMap::new();
+                input += &message("msg", &nodes, present, values, *reverse, &mut values_by_key);
+                input += "\r\n";
+                expected.push(values_by_key);
+            }
+            let mut src = EventStream::new(input.as_bytes());
+            for expected in expected {
+                let parsed: HashMap<String, String> = run_parser(&mut src, &parser)
+                    .map_err(|e| proptest::test_runner::TestCaseError::fail(e.to_string()))?
+                    .into_iter()
+                    .map(|(key, value)| (key.to_string(), value))
+                    .collect();
+                proptest::prop_assert_eq!(parsed, expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,492 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Days, Local, NaiveTime, TimeZone};
+use uuid::Uuid;
+
+use crate::config::{Config, DeltaConfig, Derived, OverproductionConfig, TimestampSource};
+use crate::error::Error;
+use crate::parser::{EventStream, Parser, run_parser_into};
+
+/// The values from a single message, as numbers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reading {
+    /// Made up for the reading when it's received, so that a backend can tell a reading
+    /// it's sent twice from two with the same values.
+    pub id: Uuid,
+    /// When the reading was received.
+    pub received: SystemTime,
+    /// When the reading was taken, by whichever clock the config says. Until the config
+    /// is applied, this is when it was received.
+    pub timestamp: SystemTime,
+    /// Time of day from the sensor's clock, as `HH:MM:SS`.
+    pub time: Option<String>,
+    /// Temperature at the sensor.
+    pub temperature: Option<f64>,
+    /// Whether the sensor's battery is low, for sensors that say.
+    pub low_battery: Option<bool>,
+    /// Strength of the sensor's radio link, for sensors that say.
+    pub link: Option<i64>,
+    /// The monitor's firmware, like `CC128-v0.11`, for monitors that say.
+    pub firmware: Option<String>,
+    /// Power use in watts, by channel.
+    pub watts: BTreeMap<String, f64>,
+    /// The change in watts since the previous reading, by channel, for the channels
+    /// `Deltas` was given.
+    pub deltas: BTreeMap<String, f64>,
+    /// Whether solar is making more than is being used, as worked out by `Overproduction`.
+    pub exporting: Option<bool>,
+    /// Fields that were left out, as their values were bad.
+    pub failed: Vec<String>,
+    /// The message's raw XML, if it's being archived.
+    pub raw: Option<String>,
+    /// Values kept as text, by key, and the contents of the message's other tags, by path
+    /// like `msg/src`, if everything is being passed through.
+    pub extra: BTreeMap<String, String>
+}
+
+impl Reading {
+    /// Convert the map produced by the parser. Keys in `text_keys` are kept as text in
+    /// `extra`, and keys other than those, `time`, `temperature`, `low_battery`, `link` and
+    /// `firmware` are taken to be channels.
+    ///
+    /// Fields with bad values are left out, and listed in `failed`, so that one bad
+    /// channel doesn't lose the rest. The errors for them are returned alongside.
+    pub fn from_fields(fields: &HashMap<&'static str, String>, text_keys: &[String])
+        -> (Reading, Vec<Error>)
+    {
+        let now = SystemTime::now();
+        let mut reading = Reading {
+            id: Uuid::new_v4(),
+            received: now,
+            timestamp: now,
+            time: None,
+            temperature: None,
+            low_battery: None,
+            link: None,
+            firmware: None,
+            watts: BTreeMap::new(),
+            deltas: BTreeMap::new(),
+            exporting: None,
+            failed: vec![],
+            raw: None,
+            extra: BTreeMap::new()
+        };
+        let mut errors = vec![];
+        for (&key, value) in fields {
+            let result = match key {
+                "time" => {
+                    reading.time = Some(value.clone());
+                    Ok(())
+                }
+                "temperature" => parse_number(key, value).map(|t| reading.temperature = Some(t)),
+                "low_battery" => {
+                    parse_number(key, value).map(|b: i64| reading.low_battery = Some(b != 0))
+                }
+                "link" => parse_number(key, value).map(|link| reading.link = Some(link)),
+                "firmware" => {
+                    reading.firmware = Some(value.clone());
+                    Ok(())
+                }
+                _ if text_keys.iter().any(|text_key| text_key == key) => {
+                    reading.extra.insert(key.to_string(), value.clone());
+                    Ok(())
+                }
+                channel => {
+                    parse_watts(key, value)
+                        .map(|watts| { reading.watts.insert(channel.to_string(), watts); })
+                }
+            };
+            if let Err(e) = result {
+                reading.failed.push(key.to_string());
+                errors.push(e);
+            }
+        }
+        reading.failed.sort();
+        (reading, errors)
+    }
+
+    /// Read the next message from the stream, and convert it to a reading as the config
+    /// says. `data` is the map to parse the message into, re-used between messages.
+    ///
+    /// Errors for fields with bad values are returned alongside, as by `from_fields`.
+    pub fn read<T: Read>(src: &mut EventStream<T>, parser: &Parser, config: &Config,
+                         data: &mut HashMap<&'static str, String>)
+        -> Result<(Reading, Vec<Error>), Error>
+    {
+        run_parser_into(src, parser, data)?;
+
+        let (mut reading, errors) = Reading::from_fields(data, &config.text_keys);
+        reading.raw = src.raw_message().map(|raw| String::from_utf8_lossy(raw).into_owned());
+        if let Some(captured) = src.captured() {
+            reading.extra.extend(captured.iter().map(|(path, text)| (path.clone(), text.clone())));
+        }
+        reading.apply_config(config);
+        Ok((reading, errors))
+    }
+
+    /// Apply the timestamp source, calibration and derived channels from the config.
+    ///
+    /// Channels are scaled and then offset, before any derived channels are computed from
+    /// them. Everything is then rounded to the configured precision, so that every backend
+    /// gets values like `21.5`, not `21.500000000000004`.
+    pub fn apply_config(&mut self, config: &Config) {
+        self.timestamp = match config.timestamp {
+            TimestampSource::Received => self.received,
+            TimestampSource::Device => self.device_timestamp().unwrap_or(self.received)
+        };
+        for (channel, value) in &mut self.watts {
+            if let Some(&scale) = config.scale.get(channel) {
+                *value *= scale;
+            }
+            if let Some(&offset) = config.offsets.get(channel) {
+                *value += offset;
+                if config.clamp_offsets && *value < 0.0 {
+                    *value = 0.0;
+                }
+            }
+        }
+        for rule in &config.derived {
+            self.derive(rule);
+        }
+        for (channel, value) in &mut self.watts {
+            let places = config.channel_precision.get(channel).unwrap_or(&config.precision);
+            *value = round(*value, *places);
+        }
+        if let Some(ref mut temperature) = self.temperature {
+            *temperature = round(*temperature, config.temperature_precision);
+        }
+    }
+
+    /// The device's time of day, on the date that puts it closest to when the reading was
+    /// received.
+    ///
+    /// This allows for the clocks being out by up to 12 hours, either side of midnight.
+    fn device_timestamp(&self) -> Option<SystemTime> {
+        let time = NaiveTime::parse_from_str(self.time.as_ref()?.trim(), "%H:%M:%S").ok()?;
+        let received: DateTime<Local> = self.received.into();
+        let today = received.date_naive();
+        let dates = [today.checked_sub_days(Days::new(1)), Some(today),
+                     today.checked_add_days(Days::new(1))];
+        dates.iter()
+            .flatten()
+            // Times skipped by a DST change don't exist, and repeated ones take the first.
+            .filter_map(|date| Local.from_local_datetime(&date.and_time(time)).earliest())
+            .min_by_key(|timestamp| (*timestamp - received).num_seconds().abs())
+            .map(SystemTime::from)
+    }
+
+    /// Add a derived channel, unless any of the channels it's computed from are missing.
+    pub fn derive(&mut self, rule: &Derived) {
+        let mut value = match self.watts.get(&rule.from) {
+            Some(&value) => value,
+            None => return
+        };
+        for channel in &rule.minus {
+            match self.watts.get(channel) {
+                Some(&other) => value -= other,
+                None => return
+            }
+        }
+        if rule.clamp && value < 0.0 {
+            value = 0.0;
+        }
+        self.watts.insert(rule.key.clone(), value);
+    }
+}
+
+/// Works out the change in some channels from one reading to the next.
+pub struct Deltas {
+    /// The channels, and the decimal places to round each one's delta to.
+    channels: Vec<(String, u32)>,
+    max_gap: Duration,
+    /// When the previous reading with each channel was received, and its watts.
+    previous: HashMap<String, (SystemTime, f64)>
+}
+
+impl Deltas {
+    /// Deltas for the channels in `delta`, rounded like the channels themselves are
+    /// in `config`.
+    pub fn new(delta: &DeltaConfig, config: &Config) -> Deltas {
+        let channels = delta.channels.iter()
+            .map(|channel| {
+                let places = config.channel_precision.get(channel).unwrap_or(&config.precision);
+                (channel.clone(), *places)
+            })
+            .collect();
+        Deltas {
+            channels,
+            max_gap: Duration::from_secs(delta.max_gap),
+            previous: HashMap::new()
+        }
+    }
+
+    /// Add the deltas to a reading, once the config's been applied to it.
+    ///
+    /// There's no delta for the first reading with a channel, or for one more than
+    /// `max_gap` after the previous one with it, as after missing readings or reconnecting,
+    /// so that it doesn't show a jump that was really spread over the gap.
+    pub fn apply(&mut self, reading: &mut Reading) {
+        for (channel, places) in &self.channels {
+            let watts = match reading.watts.get(channel) {
+                Some(&watts) => watts,
+                None => {
+                    self.previous.remove(channel);
+                    continue;
+                }
+            };
+            let previous = self.previous.insert(channel.clone(), (reading.received, watts));
+            if let Some((received, previous_watts)) = previous {
+                let gap = reading.received.duration_since(received);
+                if gap.is_ok_and(|gap| gap <= self.max_gap) {
+                    reading.deltas.insert(channel.clone(), round(watts - previous_watts, *places));
+                }
+            }
+        }
+    }
+}
+
+/// Works out whether solar is making more than is being used, from one reading to the
+/// next.
+pub struct Overproduction {
+    solar: String,
+    consumption: String,
+    readings: u32,
+    exporting: bool,
+    /// Readings in a row so far that disagree with `exporting`.
+    disagreeing: u32
+}
+
+impl Overproduction {
+    pub fn new(config: &OverproductionConfig) -> Overproduction {
+        Overproduction {
+            solar: config.solar.clone(),
+            consumption: config.consumption.clone(),
+            readings: config.readings,
+            exporting: false,
+            disagreeing: 0
+        }
+    }
+
+    /// Set whether a reading is exporting, once the config's been applied to it.
+    ///
+    /// Exporting only starts or stops once enough readings in a row agree, and it's left
+    /// as it was, but unknown, for readings without both channels. Returns the new state
+    /// when it changes.
+    pub fn apply(&mut self, reading: &mut Reading) -> Option<bool> {
+        let (solar, consumption) = match (reading.watts.get(&self.solar),
+                                          reading.watts.get(&self.consumption)) {
+            (Some(&solar), Some(&consumption)) => (solar, consumption),
+            _ => return None
+        };
+        let changed = if (solar > consumption) != self.exporting {
+            self.disagreeing += 1;
+            if self.disagreeing >= self.readings {
+                self.exporting = !self.exporting;
+                self.disagreeing = 0;
+                Some(self.exporting)
+            } else {
+                None
+            }
+        } else {
+            self.disagreeing = 0;
+            None
+        };
+        reading.exporting = Some(self.exporting);
+        changed
+    }
+}
+
+/// What's done to each reading once the config's been applied to it, by both the program
+/// and `ReadingStream`: repeats are spotted, then deltas and overproduction are worked out,
+/// as the config says.
+pub struct Pipeline {
+    dedupe: Dedupe,
+    deltas: Option<Deltas>,
+    overproduction: Option<Overproduction>,
+    /// The monitor's firmware, as of the last reading that had it.
+    firmware: Option<String>
+}
+
+/// What `Pipeline::apply` found worth telling about a reading.
+#[derive(Debug, Default, PartialEq)]
+pub struct Applied {
+    /// The monitor's firmware, if it's the first reading with it, or it's changed, like
+    /// after the monitor is updated.
+    pub firmware: Option<String>,
+    /// The sensor id and time, if the reading is a repeat of the last one from that
+    /// sensor, and should be dropped.
+    pub repeat: Option<(String, String)>,
+    /// Whether solar is exporting, if that's changed with this reading.
+    pub exporting: Option<bool>
+}
+
+impl Pipeline {
+    pub fn new(config: &Config) -> Pipeline {
+        Pipeline {
+            dedupe: Dedupe::new(config.sensor_key.clone()),
+            deltas: config.delta.as_ref().map(|delta| Deltas::new(delta, config)),
+            overproduction: config.overproduction.as_ref().map(Overproduction::new),
+            firmware: None
+        }
+    }
+
+    /// Apply the pipeline to a reading. A repeat is left as it was, as counting it again
+    /// would count its energy twice in a summary, and its deltas would all be 0.
+    pub fn apply(&mut self, reading: &mut Reading) -> Applied {
+        let mut applied = Applied::default();
+        if reading.firmware.is_some() && reading.firmware != self.firmware {
+            self.firmware = reading.firmware.clone();
+            applied.firmware = reading.firmware.clone();
+        }
+        applied.repeat = self.dedupe.repeat(reading);
+        if applied.repeat.is_some() {
+            return applied;
+        }
+        if let Some(ref mut deltas) = self.deltas {
+            deltas.apply(reading);
+        }
+        applied.exporting = self.overproduction.as_mut().and_then(|o| o.apply(reading));
+        applied
+    }
+}
+
+/// The time last read from each sensor, to spot readings that arrive twice.
+struct Dedupe {
+    /// The text key holding the sensor id, if readings are deduplicated at all.
+    key: Option<String>,
+    last_times: HashMap<String, String>
+}
+
+impl Dedupe {
+    fn new(key: Option<String>) -> Dedupe {
+        Dedupe { key, last_times: HashMap::new() }
+    }
+
+    /// The sensor id and time of the reading, if they're the same as the last reading
+    /// from that sensor. Readings without either are never repeats.
+    fn repeat(&mut self, reading: &Reading) -> Option<(String, String)> {
+        let sensor = reading.extra.get(self.key.as_ref()?)?;
+        let time = reading.time.as_ref()?;
+        if self.last_times.get(sensor) == Some(time) {
+            return Some((sensor.clone(), time.clone()));
+        }
+        self.last_times.insert(sensor.clone(), time.clone());
+        None
+    }
+}
+
+/// Round to the given number of decimal places.
+pub fn round(value: f64, places: u32) -> f64 {
+    let factor = 10f64.powi(places as i32);
+    (value * factor).round() / factor
+}
+
+fn parse_number<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, Error> {
+    value.trim().parse().map_err(|_| Error::Parse(format!("Bad value for {}: {:?}", key, value)))
+}
+
+/// Watts can have decimals, for monitors that report them, but must be finite.
+fn parse_watts(key: &str, value: &str) -> Result<f64, Error> {
+    parse_number(key, value).and_then(|watts: f64| {
+        if watts.is_finite() {
+            Ok(watts)
+        } else {
+            Err(Error::Parse(format!("Bad value for {}: {:?}", key, value)))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(values: &[(&'static str, &str)]) -> Reading {
+        let fields = values.iter().map(|&(key, value)| (key, value.to_string())).collect();
+        let (reading, errors) = Reading::from_fields(&fields, &[]);
+        assert!(errors.is_empty());
+        reading
+    }
+
+    fn watts(reading: &Reading) -> Vec<(&str, f64)> {
+        reading.watts.iter().map(|(channel, &watts)| (&channel[..], watts)).collect()
+    }
+
+    #[test]
+    fn scale_then_offset_then_derive_then_round() {
+        let mut config = Config::default();
+        config.scale.insert("total".into(), 1.5);
+        config.offsets.insert("total".into(), 0.26);
+        config.derived.push(Derived {
+            key: "other".into(),
+            from: "total".into(),
+            minus: vec!["solar".into()],
+            clamp: false
+        });
+        config.precision = 1;
+        let mut reading = reading(&[("total", "101"), ("solar", "50.44"),
+                                    ("temperature", "21.549")]);
+        reading.apply_config(&config);
+        // Offsetting first would give 151.9, and rounding before deriving 101.4.
+        assert_eq!(watts(&reading), [("other", 101.3), ("solar", 50.4), ("total", 151.8)]);
+        assert_eq!(reading.temperature, Some(21.5));
+    }
+
+    #[test]
+    fn clamp_offsets() {
+        let calibrated = |config: &Config| {
+            let mut reading = reading(&[("total", "10"), ("solar", "-5")]);
+            reading.apply_config(config);
+            reading.watts
+        };
+        let mut config = Config::default();
+        config.offsets.insert("total".into(), -30.0);
+        assert_eq!(calibrated(&config),
+                   BTreeMap::from([("solar".into(), -5.0), ("total".into(), -20.0)]));
+
+        // Only the channel with an offset is clamped.
+        config.clamp_offsets = true;
+        assert_eq!(calibrated(&config),
+                   BTreeMap::from([("solar".into(), -5.0), ("total".into(), 0.0)]));
+    }
+
+    #[test]
+    fn pipeline() {
+        let config = Config {
+            sensor_key: Some("sensor".into()),
+            delta: Some(DeltaConfig::default()),
+            ..Config::default()
+        };
+        let mut pipeline = Pipeline::new(&config);
+        let mut apply = |time: &str, total: &str| {
+            let fields = HashMap::from([("sensor", "0".to_string()), ("time", time.to_string()),
+                                        ("total", total.to_string()),
+                                        ("firmware", "CC128-v0.11".to_string())]);
+            let mut reading = Reading::from_fields(&fields, &["sensor".into()]).0;
+            let applied = pipeline.apply(&mut reading);
+            (applied, reading.deltas.get("total").copied())
+        };
+
+        let (applied, delta) = apply("13:02:39", "345");
+        assert_eq!(applied.firmware.as_deref(), Some("CC128-v0.11"));
+        assert_eq!(delta, None);
+        // A repeat is dropped, and doesn't count towards the next delta.
+        let (applied, delta) = apply("13:02:39", "345");
+        assert_eq!(applied.repeat, Some(("0".into(), "13:02:39".into())));
+        assert_eq!(delta, None);
+        let (applied, delta) = apply("13:02:45", "350");
+        assert_eq!(applied, Applied::default());
+        assert_eq!(delta, Some(5.0));
+    }
+
+    #[test]
+    fn rounding() {
+        assert_eq!(round(21.549, 1), 21.5);
+        assert_eq!(round(21.5 + 1e-15, 1), 21.5);
+        assert_eq!(round(1234.5678, 2), 1234.57);
+        assert_eq!(round(2.5, 0), 3.0);
+        assert_eq!(round(-2.5, 0), -3.0);
+        assert_eq!(round(345.0, 0), 345.0);
+    }
+}
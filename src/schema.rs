@@ -0,0 +1,233 @@
+//! Parsers described in a JSON file, for monitors that `fields` can't describe.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::error::Error;
+use crate::parser::{Parser, leak};
+use crate::parser::Parser::*;
+
+/// A tag in a schema file, and what to read from it. The file holds the outermost tag:
+///
+/// ```json
+/// {"tag": "msg", "children": [
+///     {"tag": "time", "key": "time"},
+///     {"tag": "tmpr", "key": "temperature", "optional": true},
+///     {"tag": "sensor", "attributes": {"id": "sensor_id"}},
+///     {"tag": "ch1", "children": [{"tag": "watts", "key": "total"}]}
+/// ]}
+/// ```
+///
+/// Each tag has either a `key` to store its text under, or `children` and `attributes`.
+/// An attribute can be optional too, as in `{"id": {"key": "sensor_id", "optional": true}}`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SchemaTag {
+    pub tag: String,
+    /// The key to store the tag's text under.
+    #[serde(default)]
+    pub key: Option<String>,
+    /// The tags inside it.
+    #[serde(default)]
+    pub children: Vec<SchemaTag>,
+    /// The keys to store its attributes under, by attribute name.
+    #[serde(default)]
+    pub attributes: BTreeMap<String, SchemaAttribute>,
+    /// Whether messages without the tag are still read.
+    #[serde(default)]
+    pub optional: bool
+}
+
+/// The key to store an attribute under, on its own or with whether messages without the
+/// attribute are still read.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum SchemaAttribute {
+    Key(String),
+    Optional(OptionalAttribute)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OptionalAttribute {
+    pub key: String,
+    #[serde(default)]
+    pub optional: bool
+}
+
+impl SchemaAttribute {
+    pub fn key(&self) -> &str {
+        match *self {
+            SchemaAttribute::Key(ref key) => key,
+            SchemaAttribute::Optional(ref attribute) => &attribute.key
+        }
+    }
+
+    pub fn optional(&self) -> bool {
+        match *self {
+            SchemaAttribute::Key(_) => false,
+            SchemaAttribute::Optional(ref attribute) => attribute.optional
+        }
+    }
+}
+
+impl SchemaTag {
+    /// Load a schema file, and check it describes a parser.
+    ///
+    /// Problems are returned one per line, each starting with the file and the path of the
+    /// tag it's about, like `envi.json: msg/ch1: needs a key, children or attributes`.
+    pub fn load(path: &Path) -> Result<SchemaTag, Error> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+        let schema: SchemaTag = serde_json::from_str(&contents)
+            .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+        let problems = schema.problems();
+        if problems.is_empty() {
+            Ok(schema)
+        } else {
+            let lines: Vec<String> = problems.iter()
+                .map(|problem| format!("{}: {}", path.display(), problem))
+                .collect();
+            Err(Error::Config(lines.join("\n")))
+        }
+    }
+
+    /// Everything wrong with the schema, if it's the outermost tag.
+    fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.key.is_some() || (self.children.is_empty() && self.attributes.is_empty()) {
+            problems.push(format!("{}: needs children or attributes and no key, as the \
+                                   outermost tag", self.tag));
+        }
+        if self.optional {
+            problems.push(format!("{}: the outermost tag can't be optional", self.tag));
+        }
+        let mut keys = vec![];
+        self.check_attributes(&self.tag, &mut keys, &mut problems);
+        for child in &self.children {
+            child.check(&self.tag, &mut keys, &mut problems);
+        }
+        problems
+    }
+
+    fn check<'a>(&'a self, parent: &str, keys: &mut Vec<&'a str>, problems: &mut Vec<String>) {
+        let path = format!("{}/{}", parent, self.tag);
+        if self.tag.is_empty() {
+            problems.push(format!("{}: empty tag name", path));
+        }
+        let inside = !self.children.is_empty() || !self.attributes.is_empty();
+        match (&self.key, inside) {
+            (Some(key), false) => check_key(&path, key, keys, problems),
+            (Some(_), true) => {
+                problems.push(format!("{}: has a key, so can't have children or attributes",
+                                      path));
+            }
+            (None, false) => {
+                problems.push(format!("{}: needs a key, children or attributes", path));
+            }
+            (None, true) => ()
+        }
+        self.check_attributes(&path, keys, problems);
+        for child in &self.children {
+            child.check(&path, keys, problems);
+        }
+    }
+
+    fn check_attributes<'a>(&'a self, path: &str, keys: &mut Vec<&'a str>,
+                            problems: &mut Vec<String>)
+    {
+        for (name, attribute) in &self.attributes {
+            check_key(&format!("{}@{}", path, name), attribute.key(), keys, problems);
+        }
+    }
+
+    /// Every key the schema stores values under.
+    pub fn keys(&self) -> Vec<&str> {
+        let mut keys: Vec<&str> = self.key.iter().map(|key| &key[..]).collect();
+        keys.extend(self.attributes.values().map(|attribute| attribute.key()));
+        keys.extend(self.children.iter().flat_map(|child| child.keys()));
+        keys
+    }
+
+    /// The parser for the schema, leaving out tags for keys that `enabled` returns false
+    /// for, and tags with nothing left inside.
+    ///
+    /// This should only be called once, as the parser's names are never freed.
+    pub fn parser<F: Fn(&str) -> bool>(&self, enabled: &F) -> Parser {
+        Top(leak(&self.tag), self.subparsers(enabled))
+    }
+
+    /// The parsers for the tag's attributes and children.
+    fn subparsers<F: Fn(&str) -> bool>(&self, enabled: &F) -> Vec<Parser> {
+        let attributes = self.attributes.iter()
+            .filter(|&(_, attribute)| enabled(attribute.key()))
+            .map(|(name, attribute)| {
+                let parser = Attribute(leak(name), leak(attribute.key()));
+                if attribute.optional() { Optional(Box::new(parser)) } else { parser }
+            });
+        let children = self.children.iter().filter_map(|child| child.subparser(enabled));
+        attributes.chain(children).collect()
+    }
+
+    fn subparser<F: Fn(&str) -> bool>(&self, enabled: &F) -> Option<Parser> {
+        let parser = match self.key {
+            Some(ref key) if enabled(key) => Contents(leak(&self.tag), leak(key)),
+            Some(_) => return None,
+            None => {
+                let subparsers = self.subparsers(enabled);
+                if subparsers.is_empty() {
+                    return None;
+                }
+                Tag(leak(&self.tag), subparsers)
+            }
+        };
+        if self.optional {
+            Some(Optional(Box::new(parser)))
+        } else {
+            Some(parser)
+        }
+    }
+}
+
+fn check_key<'a>(path: &str, key: &'a str, keys: &mut Vec<&'a str>, problems: &mut Vec<String>) {
+    if keys.contains(&key) {
+        problems.push(format!("{}: key {} is already used", path, key));
+    }
+    keys.push(key);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::{EventStream, run_parser};
+    use super::*;
+
+    #[test]
+    fn optional_attributes() {
+        let schema: SchemaTag = serde_json::from_str(r#"
+            {"tag": "msg", "children": [
+                {"tag": "sensor", "attributes": {
+                    "id": "sensor_id",
+                    "type": {"key": "sensor_type", "optional": true}
+                }},
+                {"tag": "ch1", "children": [{"tag": "watts", "key": "total"}]}
+            ]}
+        "#).unwrap();
+        assert!(schema.problems().is_empty(), "{:?}", schema.problems());
+        let parser = schema.parser(&|_| true);
+        // A message with the given sensor tag.
+        let read = |sensor: &str| {
+            let xml = format!("<msg>{}<ch1><watts>345</watts></ch1></msg>", sensor);
+            run_parser(&mut EventStream::new(xml.as_bytes()), &parser)
+        };
+
+        let fields = read(r#"<sensor id="1" type="2"/>"#).unwrap();
+        assert_eq!((&fields["sensor_id"][..], &fields["sensor_type"][..]), ("1", "2"));
+        let fields = read(r#"<sensor id="1"/>"#).unwrap();
+        assert!(!fields.contains_key("sensor_type"));
+        assert_eq!(read(r#"<sensor type="2"/>"#).unwrap_err().to_string(),
+                   "Missing attribute id on <sensor>");
+    }
+}
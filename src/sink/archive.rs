@@ -0,0 +1,90 @@
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::reading::Reading;
+use super::Sink;
+use super::file::{LogFile, Rotation};
+
+/// Appends the raw XML of each message to a file, one message per line, optionally
+/// gzipped. Line breaks within a message are written as spaces, which reads the same, as
+/// whitespace around text is trimmed.
+///
+/// Lines are buffered and rotated like those of the `JsonlSink`. Readings without their
+/// raw XML are skipped.
+pub struct ArchiveSink {
+    file: LogFile,
+    flush_interval: Duration,
+    last_flush: Instant
+}
+
+impl ArchiveSink {
+    pub fn open(path: &Path, gzip: bool, rotation: Option<Rotation>, flush_interval: Duration)
+        -> Result<ArchiveSink, Error>
+    {
+        Ok(ArchiveSink {
+            file: LogFile::open(path, gzip, rotation)?,
+            flush_interval,
+            last_flush: Instant::now()
+        })
+    }
+}
+
+impl Sink for ArchiveSink {
+    fn send(&mut self, reading: &Reading) -> Result<(), Error> {
+        let raw = match reading.raw {
+            Some(ref raw) => raw,
+            None => return Ok(())
+        };
+        let line = raw.replace("\r\n", " ").replace(['\r', '\n'], " ");
+        self.file.write_all(line.as_bytes())
+            .and_then(|()| self.file.write_all(b"\n"))
+            .map_err(|e| Error::Backend(format!("Error archiving XML: {}", e)))?;
+        if self.last_flush.elapsed() >= self.flush_interval {
+            self.flush()?;
+        }
+        self.file.rotate_if_needed()
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.last_flush = Instant::now();
+        self.file.flush()
+            .map_err(|e| Error::Backend(format!("Error archiving XML: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+
+    use uuid::Uuid;
+
+    use crate::parser::{EventStream, envi_parser, run_parser};
+    use super::*;
+
+    #[test]
+    fn message_per_line() {
+        let raw = include_str!("../../tests/fixtures/live_indented.xml").trim_end();
+        let mut reading = Reading::from_fields(&HashMap::new(), &[]).0;
+        reading.raw = Some(raw.replace('\n', "\r\n"));
+
+        let path = std::env::temp_dir()
+            .join(format!("household-power-{}.xml", Uuid::new_v4()));
+        let mut sink = ArchiveSink::open(&path, false, None, Duration::from_secs(0)).unwrap();
+        sink.send(&reading).unwrap();
+        reading.raw = Some(raw.to_string());
+        sink.send(&reading).unwrap();
+        drop(sink);
+        let written = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let lines: Vec<_> = written.lines().collect();
+        assert_eq!(lines.len(), 2, "{}", written);
+        let fields = |xml: &str| run_parser(&mut EventStream::new(xml.as_bytes()), &envi_parser());
+        for line in lines {
+            assert_eq!(fields(line).unwrap(), fields(raw).unwrap());
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local, SecondsFormat};
+use flate2::read::MultiGzDecoder;
+
+use crate::error::Error;
+use crate::reading::Reading;
+use super::Sink;
+use super::file::{LogFile, Rotation};
+
+/// The columns every file starts with, before the channels.
+const COLUMNS: [&str; 5] = ["timestamp", "time", "temperature", "low_battery", "link"];
+
+/// Appends every reading to a CSV file, optionally gzipped, with a header row.
+///
+/// The columns are `timestamp` (in ISO 8601, with the local offset), `time`,
+/// `temperature`, `low_battery` and `link`, then the channels in the order given.
+/// Missing values are left empty. Lines are buffered and rotated like those of the
+/// `JsonlSink`, and each rotated file gets a header of its own.
+pub struct CsvSink {
+    file: LogFile,
+    channels: Vec<String>,
+    flush_interval: Duration,
+    last_flush: Instant
+}
+
+impl CsvSink {
+    /// Open the file, which if it already has readings must have the same columns.
+    pub fn open(path: &Path, gzip: bool, rotation: Option<Rotation>, flush_interval: Duration,
+                channels: Vec<String>)
+        -> Result<CsvSink, Error>
+    {
+        let columns: Vec<&str> = COLUMNS.iter().cloned()
+            .chain(channels.iter().map(|channel| &channel[..]))
+            .collect();
+        let header = format!("{}\n", columns.join(","));
+        match existing_header(path, gzip) {
+            Ok(Some(ref existing)) if *existing != header => {
+                return Err(Error::Backend(format!(
+                    "{} has columns {}, not {}. Move it out of the way to start a new one.",
+                    path.display(), existing.trim_end(), header.trim_end())));
+            }
+            Err(e) => return Err(Error::Backend(format!("{}: {}", path.display(), e))),
+            _ => ()
+        }
+
+        Ok(CsvSink {
+            file: LogFile::open_with_header(path, gzip, rotation, header.into_bytes())?,
+            channels,
+            flush_interval,
+            last_flush: Instant::now()
+        })
+    }
+
+    fn write(&mut self, reading: &Reading) -> io::Result<()> {
+        let timestamp = DateTime::<Local>::from(reading.timestamp);
+        let mut fields = vec![
+            timestamp.to_rfc3339_opts(SecondsFormat::Secs, false),
+            reading.time.as_ref().map(|time| escape(time)).unwrap_or_default(),
+            optional(reading.temperature),
+            optional(reading.low_battery),
+            optional(reading.link)
+        ];
+        fields.extend(self.channels.iter().map(|channel| optional(reading.watts.get(channel))));
+        self.file.write_all(fields.join(",").as_bytes())?;
+        self.file.write_all(b"\n")
+    }
+}
+
+/// The first line of the file, if it exists and isn't empty.
+fn existing_header(path: &Path, gzip: bool) -> io::Result<Option<String>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e)
+    };
+    if file.metadata()?.len() == 0 {
+        return Ok(None);
+    }
+    let reader: Box<dyn Read> = if gzip {
+        Box::new(MultiGzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line)?;
+    Ok(Some(line))
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+/// Quote a field if it has anything in it that CSV treats specially.
+fn escape(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+impl Sink for CsvSink {
+    fn send(&mut self, reading: &Reading) -> Result<(), Error> {
+        self.write(reading)
+            .map_err(|e| Error::Backend(format!("Error writing CSV: {}", e)))?;
+        if self.last_flush.elapsed() >= self.flush_interval {
+            self.flush()?;
+        }
+        self.file.rotate_if_needed()
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.last_flush = Instant::now();
+        self.file.flush()
+            .map_err(|e| Error::Backend(format!("Error writing CSV: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+
+    use uuid::Uuid;
+
+    use crate::config::Config;
+    use super::*;
+
+    #[test]
+    fn rounded_values() {
+        let mut config = Config::default();
+        config.scale.insert("hot_water".into(), 0.1);
+        config.channel_precision.insert("hot_water".into(), 1);
+        config.channel_precision.insert("solar".into(), 2);
+        let fields = HashMap::from([("temperature", "21.549".to_string()),
+                                    ("total", "345.4".to_string()),
+                                    ("hot_water", "2151".to_string()),
+                                    ("solar", "12.3456".to_string())]);
+        let mut reading = Reading::from_fields(&fields, &[]).0;
+        reading.apply_config(&config);
+
+        let path = std::env::temp_dir()
+            .join(format!("household-power-{}.csv", Uuid::new_v4()));
+        let channels = vec!["total".into(), "hot_water".into(), "solar".into()];
+        let mut sink = CsvSink::open(&path, false, None, Duration::from_secs(0), channels)
+            .unwrap();
+        sink.send(&reading).unwrap();
+        drop(sink);
+        let written = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let (header, row) = written.split_once('\n').unwrap();
+        assert_eq!(header, "timestamp,time,temperature,low_battery,link,total,hot_water,solar");
+        // After the timestamp, which is in local time.
+        let (_, row) = row.split_once(',').unwrap();
+        assert_eq!(row, ",21.5,,,345,215.1,12.35\n");
+    }
+}
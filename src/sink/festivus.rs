@@ -0,0 +1,87 @@
+use festivus_client::Festivus;
+
+use crate::error::Error;
+use crate::reading::Reading;
+use super::Sink;
+
+/// Sends three channels to a Festivus server, as its `total`, `hot_water` and `solar`
+/// fields.
+pub struct FestivusSink {
+    url: String,
+    client: Festivus,
+    /// The channel to send as each field, in the order Festivus takes them.
+    channels: [String; 3],
+    /// What to send for a channel the reading doesn't have, if it's sent at all.
+    missing: Option<i64>
+}
+
+impl FestivusSink {
+    /// A sink sending the channels in `channels` as Festivus's `total`, `hot_water` and
+    /// `solar`, in that order. A channel that's missing from a reading, as it's disabled,
+    /// is sent as `missing`, or if that's `None`, the reading isn't sent.
+    pub fn new(url: &str, channels: [&str; 3], missing: Option<i64>) -> FestivusSink {
+        FestivusSink {
+            url: url.to_string(),
+            client: Festivus::new(url),
+            channels: channels.map(String::from),
+            missing
+        }
+    }
+
+    /// The values to send for each field, in whole watts.
+    fn fields(&self, reading: &Reading) -> Result<[i64; 3], Error> {
+        // Leaving out a channel that couldn't be read would record it as 0.
+        if let Some(channel) = reading.failed.iter().find(|&key| self.channels.contains(key)) {
+            return Err(Error::Backend(format!("Not sending to Festivus at {}, as {} couldn't \
+                                               be read", self.url, channel)));
+        }
+        let mut fields = [0; 3];
+        for (field, channel) in fields.iter_mut().zip(&self.channels) {
+            *field = match (reading.watts.get(channel), self.missing) {
+                (Some(&watts), _) => watts.round() as i64,
+                (None, Some(missing)) => missing,
+                (None, None) => {
+                    return Err(Error::Backend(format!("Not sending to Festivus at {}, as the \
+                                                       reading has no {}", self.url, channel)));
+                }
+            };
+        }
+        Ok(fields)
+    }
+}
+
+impl Sink for FestivusSink {
+    fn send(&mut self, reading: &Reading) -> Result<(), Error> {
+        // Whichever integer type the client takes.
+        let [total, hot_water, solar] = self.fields(reading)?;
+        self.client.insert(total as _, hot_water as _, solar as _)
+            .map_err(|e| {
+                Error::Backend(format!("Error connecting to Festivus at {}: {:?}", self.url, e))
+            })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn reading(values: &[(&'static str, &str)]) -> Reading {
+        let fields: HashMap<_, _> =
+            values.iter().map(|&(key, value)| (key, value.to_string())).collect();
+        Reading::from_fields(&fields, &[]).0
+    }
+
+    #[test]
+    fn disabled_channels() {
+        // Solar is disabled, so readings don't have it.
+        let reading = reading(&[("total", "345.4"), ("hot_water", "2151")]);
+        let channels = ["total", "hot_water", "solar"];
+        let sink = FestivusSink::new("http://localhost:3000", channels, None);
+        assert!(sink.fields(&reading).is_err());
+        let sink = FestivusSink::new("http://localhost:3000", channels, Some(-1));
+        assert_eq!(sink.fields(&reading).unwrap(), [345, 2151, -1]);
+    }
+}
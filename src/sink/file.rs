@@ -0,0 +1,181 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+use crate::error::Error;
+
+/// When to rotate a file, and how many old files to keep.
+///
+/// Old files are named like `readings.jsonl.1`, `readings.jsonl.2`, ..., from newest to
+/// oldest.
+#[derive(Clone, Copy, Debug)]
+pub struct Rotation {
+    /// Size in bytes beyond which the file is rotated.
+    pub max_size: u64,
+    /// Number of old files to keep.
+    pub keep: usize
+}
+
+/// A file that lines are appended to, optionally through gzip.
+///
+/// Flushing a gzipped file writes out everything so far in a form that can be read back,
+/// but the file is only complete once the `LogFile` is dropped or rotated. Appending to an
+/// existing gzipped file adds a new gzip member, which readers treat as a continuation.
+pub struct LogFile {
+    path: PathBuf,
+    gzip: bool,
+    rotation: Option<Rotation>,
+    writer: Writer,
+    /// Written at the start of every new file, like a CSV header. May be empty.
+    header: Vec<u8>,
+    /// Bytes in the file on disk, which for gzip lags behind what's been written.
+    size: Arc<AtomicU64>
+}
+
+enum Writer {
+    Plain(BufWriter<CountingFile>),
+    Gzip(BufWriter<GzEncoder<CountingFile>>)
+}
+
+/// Keeps count of the size of the file, as it's appended to.
+struct CountingFile {
+    file: File,
+    size: Arc<AtomicU64>
+}
+
+impl Write for CountingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.size.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl LogFile {
+    pub fn open(path: &Path, gzip: bool, rotation: Option<Rotation>) -> Result<LogFile, Error> {
+        LogFile::open_with_header(path, gzip, rotation, vec![])
+    }
+
+    /// Open the file, writing `header` first if it's empty, and at the start of every file
+    /// it's rotated to.
+    pub fn open_with_header(path: &Path, gzip: bool, rotation: Option<Rotation>,
+                            header: Vec<u8>)
+        -> Result<LogFile, Error>
+    {
+        let size = Arc::new(AtomicU64::new(0));
+        let mut writer = open_writer(path, gzip, &size)
+            .map_err(|e| file_error(path, e))?;
+        if size.load(Ordering::Relaxed) == 0 {
+            writer.write_all(&header).map_err(|e| file_error(path, e))?;
+        }
+        Ok(LogFile {
+            path: path.to_path_buf(),
+            gzip,
+            rotation,
+            writer,
+            header,
+            size
+        })
+    }
+
+    /// Rotate the file if it's grown too big.
+    ///
+    /// This should be called between lines, so that a line is never split across files.
+    pub fn rotate_if_needed(&mut self) -> Result<(), Error> {
+        let rotation = match self.rotation {
+            Some(rotation) if self.size.load(Ordering::Relaxed) > rotation.max_size => rotation,
+            _ => return Ok(())
+        };
+        self.rotate(rotation.keep).map_err(|e| file_error(&self.path, e))
+    }
+
+    fn rotate(&mut self, keep: usize) -> io::Result<()> {
+        // Write out everything so far before moving the file, so nothing is lost if a
+        // rename fails.
+        self.flush()?;
+
+        // Each rename replaces the file it's moved over in one step, so there's never a
+        // moment where a file is only partially moved.
+        for i in (1..keep).rev() {
+            match fs::rename(self.numbered(i), self.numbered(i + 1)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                result => result?
+            }
+        }
+        if keep > 0 {
+            fs::rename(&self.path, self.numbered(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+
+        // The old writer still has the moved file open, and finishes it off there.
+        self.size = Arc::new(AtomicU64::new(0));
+        let mut new_writer = open_writer(&self.path, self.gzip, &self.size)?;
+        new_writer.write_all(&self.header)?;
+        match mem::replace(&mut self.writer, new_writer) {
+            Writer::Plain(writer) => writer.into_inner().map_err(|e| e.into_error())?.flush()?,
+            Writer::Gzip(writer) => {
+                writer.into_inner().map_err(|e| e.into_error())?.finish()?.flush()?
+            }
+        }
+        info!("Rotated {}", self.path.display());
+        Ok(())
+    }
+
+    fn numbered(&self, i: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", i));
+        PathBuf::from(path)
+    }
+}
+
+fn open_writer(path: &Path, gzip: bool, size: &Arc<AtomicU64>) -> io::Result<Writer> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    size.store(file.metadata()?.len(), Ordering::Relaxed);
+    let file = CountingFile { file, size: size.clone() };
+    Ok(if gzip {
+        Writer::Gzip(BufWriter::new(GzEncoder::new(file, Compression::default())))
+    } else {
+        Writer::Plain(BufWriter::new(file))
+    })
+}
+
+fn file_error(path: &Path, e: io::Error) -> Error {
+    Error::Backend(format!("{}: {}", path.display(), e))
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Writer::Plain(ref mut writer) => writer.write(buf),
+            Writer::Gzip(ref mut writer) => writer.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Writer::Plain(ref mut writer) => writer.flush(),
+            Writer::Gzip(ref mut writer) => writer.flush()
+        }
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use serde::Serialize;
+use serde_json::Number;
+use uuid::Uuid;
+
+use crate::error::Error;
+use crate::reading::Reading;
+use super::Sink;
+use super::file::{LogFile, Rotation};
+
+/// Appends readings to a file, as one JSON object per line, optionally gzipped.
+///
+/// Lines are buffered, and written out once `flush_interval` has passed since the last
+/// write, when the next reading arrives. The file is rotated once it's too big, between
+/// lines.
+pub struct JsonlSink {
+    file: LogFile,
+    flush_interval: Duration,
+    last_flush: Instant
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    id: Uuid,
+    /// Seconds since the Unix epoch when the reading was taken.
+    timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    low_battery: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    firmware: Option<&'a str>,
+    #[serde(flatten)]
+    watts: BTreeMap<&'a str, Number>,
+    /// Each delta, as `total_delta` for `total`.
+    #[serde(flatten)]
+    deltas: BTreeMap<String, Number>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exporting: Option<bool>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    extra: &'a BTreeMap<String, String>
+}
+
+impl JsonlSink {
+    pub fn open(path: &Path, gzip: bool, rotation: Option<Rotation>, flush_interval: Duration)
+        -> Result<JsonlSink, Error>
+    {
+        Ok(JsonlSink {
+            file: LogFile::open(path, gzip, rotation)?,
+            flush_interval,
+            last_flush: Instant::now()
+        })
+    }
+
+    fn write(&mut self, reading: &Reading) -> Result<(), Box<dyn std::error::Error>> {
+        let line = Line {
+            id: reading.id,
+            timestamp: reading.timestamp.duration_since(UNIX_EPOCH)?.as_secs(),
+            time: reading.time.as_ref().map(|s| &s[..]),
+            temperature: reading.temperature,
+            low_battery: reading.low_battery,
+            link: reading.link,
+            firmware: reading.firmware.as_deref(),
+            watts: reading.watts.iter().map(|(channel, &watts)| (&channel[..], number(watts)))
+                .collect(),
+            deltas: reading.deltas.iter()
+                .map(|(channel, &delta)| (format!("{}_delta", channel), number(delta)))
+                .collect(),
+            exporting: reading.exporting,
+            extra: &reading.extra
+        };
+        serde_json::to_writer(&mut self.file, &line)?;
+        self.file.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+/// Whole watts are written as integers, as they always were before watts had decimals.
+fn number(watts: f64) -> Number {
+    if watts.fract() == 0.0 && watts.abs() < i64::MAX as f64 {
+        Number::from(watts as i64)
+    } else {
+        // Watts are always finite, so this never falls back to 0.
+        Number::from_f64(watts).unwrap_or_else(|| Number::from(0))
+    }
+}
+
+impl Sink for JsonlSink {
+    fn send(&mut self, reading: &Reading) -> Result<(), Error> {
+        self.write(reading)
+            .map_err(|e| Error::Backend(format!("Error writing JSON Lines: {}", e)))?;
+        if self.last_flush.elapsed() >= self.flush_interval {
+            self.flush()?;
+        }
+        self.file.rotate_if_needed()
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.last_flush = Instant::now();
+        self.file.flush()
+            .map_err(|e| Error::Backend(format!("Error writing JSON Lines: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs::{self, File};
+    use std::io::{BufRead, BufReader};
+
+    use flate2::read::MultiGzDecoder;
+    use serde_json::Value;
+
+    use crate::config::Config;
+    use super::*;
+
+    fn reading(values: &[(&'static str, &str)]) -> Reading {
+        let fields: HashMap<_, _> =
+            values.iter().map(|&(key, value)| (key, value.to_string())).collect();
+        Reading::from_fields(&fields, &[]).0
+    }
+
+    fn temp_path(extension: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("household-power-{}.{}", Uuid::new_v4(), extension))
+    }
+
+    #[test]
+    fn rounded_values() {
+        let mut config = Config::default();
+        config.scale.insert("hot_water".into(), 0.1);
+        config.channel_precision.insert("hot_water".into(), 1);
+        config.channel_precision.insert("solar".into(), 2);
+        let mut reading = reading(&[("temperature", "21.549"), ("total", "345.4"),
+                                    ("hot_water", "2151"), ("solar", "12.3456")]);
+        reading.apply_config(&config);
+        reading.id = Uuid::nil();
+        reading.timestamp = UNIX_EPOCH + Duration::from_secs(1700000000);
+
+        let path = temp_path("jsonl");
+        let mut sink = JsonlSink::open(&path, false, None, Duration::from_secs(0)).unwrap();
+        sink.send(&reading).unwrap();
+        drop(sink);
+        let written = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        // Scaling 2151 by 0.1 gives 215.10000000000002, and whole watts are integers.
+        assert_eq!(written, "{\"id\":\"00000000-0000-0000-0000-000000000000\",\
+                             \"timestamp\":1700000000,\"temperature\":21.5,\
+                             \"hot_water\":215.1,\"solar\":12.35,\"total\":345}\n");
+    }
+
+    #[test]
+    fn gzip_is_finished_on_drop() {
+        let path = temp_path("jsonl.gz");
+        // Never flushed, so everything is written out when the sink is dropped.
+        let mut sink = JsonlSink::open(&path, true, None, Duration::from_secs(3600)).unwrap();
+        sink.send(&reading(&[("total", "345")])).unwrap();
+        sink.send(&reading(&[("total", "346"), ("temperature", "18.7")])).unwrap();
+        drop(sink);
+
+        let lines = BufReader::new(MultiGzDecoder::new(File::open(&path).unwrap())).lines()
+            .map(|line| serde_json::from_str::<Value>(&line.unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["total"], 345);
+        assert_eq!(lines[1]["total"], 346);
+        assert_eq!(lines[1]["temperature"], 18.7);
+    }
+}
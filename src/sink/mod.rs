@@ -0,0 +1,75 @@
+//! Backends that readings are sent to.
+
+use crate::error::Error;
+use crate::reading::Reading;
+
+pub use self::archive::ArchiveSink;
+pub use self::csv::CsvSink;
+pub use self::festivus::FestivusSink;
+pub use self::file::Rotation;
+pub use self::jsonl::JsonlSink;
+pub use self::prometheus::{ParseErrors, PrometheusSink};
+pub use self::pushgateway::PushgatewaySink;
+pub use self::queue::{Overflow, QueueStats, QueuedSink};
+
+mod archive;
+mod csv;
+mod festivus;
+mod file;
+mod jsonl;
+mod prometheus;
+mod pushgateway;
+mod queue;
+
+/// Somewhere to send readings.
+pub trait Sink {
+    fn send(&mut self, reading: &Reading) -> Result<(), Error>;
+
+    /// Write out any readings that have been buffered.
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Sends each reading to several sinks in turn.
+///
+/// A sink failing doesn't stop the reading going to the rest.
+#[derive(Default)]
+pub struct MultiSink {
+    sinks: Vec<Box<dyn Sink + Send>>
+}
+
+impl MultiSink {
+    pub fn new() -> MultiSink {
+        MultiSink::default()
+    }
+
+    pub fn add<S: Sink + Send + 'static>(&mut self, sink: S) {
+        self.sinks.push(Box::new(sink));
+    }
+
+    /// Call `f` on every sink, and combine any errors.
+    fn for_each<F>(&mut self, mut f: F) -> Result<(), Error>
+        where F: FnMut(&mut dyn Sink) -> Result<(), Error>
+    {
+        let errors: Vec<String> = self.sinks.iter_mut()
+            .filter_map(|sink| f(&mut **sink).err())
+            .map(|e| e.to_string())
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Backend(errors.join("; ")))
+        }
+    }
+}
+
+impl Sink for MultiSink {
+    fn send(&mut self, reading: &Reading) -> Result<(), Error> {
+        self.for_each(|sink| sink.send(reading))
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.for_each(|sink| sink.flush())
+    }
+}
@@ -0,0 +1,391 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::Error;
+use crate::reading::Reading;
+use super::{QueueStats, Sink};
+
+/// Serves the latest reading as Prometheus metrics, at `/metrics`, along with the build at
+/// `/version` and whether readings are arriving at `/healthz`.
+///
+/// The watts and temperature are left out once the reading is older than `stale_after`,
+/// so that Prometheus marks them stale rather than repeating the last values. The time
+/// of the latest reading is always there, to alert on, as are how many readings are
+/// queued for each of the `queues`, how many they've dropped, and the parse errors.
+pub struct PrometheusSink {
+    latest: Arc<Mutex<Option<Reading>>>
+}
+
+impl PrometheusSink {
+    /// Start serving metrics on `address`, like `0.0.0.0:9102`, with `labels` on every
+    /// metric as well as their own. The `queues` are labelled with the backend names
+    /// they're given, and `parse_errors` are counted by whatever reads the messages.
+    pub fn serve(address: &str, stale_after: Duration, labels: &BTreeMap<String, String>,
+                 queues: Vec<(String, QueueStats)>, parse_errors: ParseErrors)
+        -> Result<PrometheusSink, Error>
+    {
+        let listener = TcpListener::bind(address)
+            .map_err(|e| Error::Backend(format!("Can't serve metrics on {}: {}", address, e)))?;
+        let latest = Arc::new(Mutex::new(None));
+        let server_latest = latest.clone();
+        let labels = format_labels(labels);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| {
+                    respond(stream, &server_latest, stale_after, &labels, &queues,
+                            &parse_errors)
+                });
+                if let Err(e) = result {
+                    debug!("Error serving metrics: {}", e);
+                }
+            }
+        });
+        Ok(PrometheusSink { latest })
+    }
+}
+
+/// Parse errors so far, and in a row, shared between whatever reads messages and the
+/// metrics.
+#[derive(Clone, Default)]
+pub struct ParseErrors {
+    shared: Arc<(AtomicU64, AtomicU32)>
+}
+
+impl ParseErrors {
+    /// Count a message that couldn't be parsed, returning how many there have been in a
+    /// row.
+    pub fn error(&self) -> u32 {
+        self.shared.0.fetch_add(1, Ordering::Relaxed);
+        self.shared.1.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Count a message that was parsed, which ends any run of errors.
+    pub fn parsed(&self) {
+        self.shared.1.store(0, Ordering::Relaxed);
+    }
+
+    /// Parse errors so far.
+    pub fn total(&self) -> u64 {
+        self.shared.0.load(Ordering::Relaxed)
+    }
+
+    /// Parse errors since the last message that was parsed.
+    pub fn in_a_row(&self) -> u32 {
+        self.shared.1.load(Ordering::Relaxed)
+    }
+}
+
+impl Sink for PrometheusSink {
+    fn send(&mut self, reading: &Reading) -> Result<(), Error> {
+        *self.latest.lock().unwrap_or_else(|e| e.into_inner()) = Some(reading.clone());
+        Ok(())
+    }
+}
+
+fn respond(mut stream: TcpStream, latest: &Mutex<Option<Reading>>, stale_after: Duration,
+           labels: &str, queues: &[(String, QueueStats)], parse_errors: &ParseErrors)
+    -> std::io::Result<()>
+{
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request_line = String::new();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    reader.read_line(&mut request_line)?;
+    // Read the rest of the request head, which isn't needed.
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    let latest = latest.lock().unwrap_or_else(|e| e.into_inner());
+    let (status, body) = match path {
+        "/metrics" => {
+            let mut body = render(latest.as_ref(), Some(stale_after), labels);
+            render_queues(&mut body, queues, labels);
+            render_parse_errors(&mut body, parse_errors, labels);
+            ("200 OK", body)
+        }
+        "/version" => ("200 OK", format!("household-power {}\n", crate::VERSION)),
+        "/healthz" => health(latest.as_ref(), stale_after),
+        _ => ("404 Not Found", "Not found\n".to_string())
+    };
+    drop(latest);
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                    Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, body.len(), body)?;
+    stream.flush()
+}
+
+/// For `/healthz`: healthy if the latest reading is no older than `stale_after`, as for
+/// the watts being served.
+fn health(latest: Option<&Reading>, stale_after: Duration) -> (&'static str, String) {
+    let age = match latest {
+        Some(reading) => SystemTime::now().duration_since(reading.received).unwrap_or_default(),
+        None => return ("503 Service Unavailable", "No readings yet\n".to_string())
+    };
+    if age > stale_after {
+        ("503 Service Unavailable", format!("Last reading {}s ago\n", age.as_secs()))
+    } else {
+        ("200 OK", "OK\n".to_string())
+    }
+}
+
+/// The metrics for the latest reading, in Prometheus's text format, with the labels from
+/// `format_labels` on each. The watts and temperature are left out if it's older than
+/// `stale_after`.
+pub(super) fn render(latest: Option<&Reading>, stale_after: Option<Duration>, labels: &str)
+    -> String
+{
+    let mut body = String::new();
+    let reading = match latest {
+        Some(reading) => reading,
+        None => return body
+    };
+
+    let received = reading.received.duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64());
+    if let Ok(received) = received {
+        gauge(&mut body, "household_power_last_reading_timestamp_seconds",
+              "When the latest reading was received, in seconds since the Unix epoch.");
+        sample(&mut body, "household_power_last_reading_timestamp_seconds", labels, received);
+    }
+
+    let age = SystemTime::now().duration_since(reading.received);
+    let stale = match (age, stale_after) {
+        (Ok(age), Some(stale_after)) => age > stale_after,
+        _ => false
+    };
+    if stale {
+        return body;
+    }
+
+    gauge(&mut body, "household_power_watts", "Latest power use in watts, by channel.");
+    for (channel, &watts) in &reading.watts {
+        let channel = format!("channel=\"{}\"", escape_label(channel));
+        let labels = if labels.is_empty() { channel } else { format!("{},{}", channel, labels) };
+        sample(&mut body, "household_power_watts", &labels, watts);
+    }
+    if let Some(temperature) = reading.temperature {
+        gauge(&mut body, "household_power_temperature_celsius",
+              "Latest temperature at the sensor, in degrees Celsius.");
+        sample(&mut body, "household_power_temperature_celsius", labels, temperature);
+    }
+    if let Some(exporting) = reading.exporting {
+        gauge(&mut body, "household_power_exporting",
+              "Whether solar is making more than is being used, as 1 or 0.");
+        sample(&mut body, "household_power_exporting", labels, if exporting { 1.0 } else { 0.0 });
+    }
+    body
+}
+
+/// The depth of each backend's queue, and the readings it's dropped.
+fn render_queues(body: &mut String, queues: &[(String, QueueStats)], labels: &str) {
+    if queues.is_empty() {
+        return;
+    }
+    let labels: Vec<String> = queues.iter()
+        .map(|(backend, _)| {
+            let backend = format!("backend=\"{}\"", escape_label(backend));
+            if labels.is_empty() { backend } else { format!("{},{}", backend, labels) }
+        })
+        .collect();
+    gauge(body, "household_power_queue_depth", "Readings waiting to be sent, by backend.");
+    for ((_, stats), labels) in queues.iter().zip(&labels) {
+        sample(body, "household_power_queue_depth", labels, stats.depth() as f64);
+    }
+    let _ = writeln!(body, "# HELP household_power_queue_dropped_total Readings dropped as the \
+                            backend's queue was full, by backend.");
+    let _ = writeln!(body, "# TYPE household_power_queue_dropped_total counter");
+    for ((_, stats), labels) in queues.iter().zip(&labels) {
+        sample(body, "household_power_queue_dropped_total", labels, stats.dropped() as f64);
+    }
+}
+
+/// The parse errors so far, and since the last message that was parsed.
+fn render_parse_errors(body: &mut String, parse_errors: &ParseErrors, labels: &str) {
+    let _ = writeln!(body, "# HELP household_power_parse_errors_total Messages that couldn't \
+                            be parsed.");
+    let _ = writeln!(body, "# TYPE household_power_parse_errors_total counter");
+    sample(body, "household_power_parse_errors_total", labels, parse_errors.total() as f64);
+    gauge(body, "household_power_parse_errors_in_a_row",
+          "Parse errors since the last message that was parsed.");
+    sample(body, "household_power_parse_errors_in_a_row", labels,
+           parse_errors.in_a_row() as f64);
+}
+
+fn gauge(body: &mut String, name: &str, help: &str) {
+    let _ = writeln!(body, "# HELP {} {}", name, help);
+    let _ = writeln!(body, "# TYPE {} gauge", name);
+}
+
+fn sample(body: &mut String, name: &str, labels: &str, value: f64) {
+    if labels.is_empty() {
+        let _ = writeln!(body, "{} {}", name, value);
+    } else {
+        let _ = writeln!(body, "{}{{{}}} {}", name, labels, value);
+    }
+}
+
+/// Labels like `host="pi",location="shed"`, for `render`.
+pub(super) fn format_labels(labels: &BTreeMap<String, String>) -> String {
+    let labels: Vec<String> = labels.iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape_label(value)))
+        .collect();
+    labels.join(",")
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::config::Config;
+    use crate::parser::EventStream;
+    use crate::sink::{Overflow, QueuedSink};
+    use super::*;
+
+    /// The fixture message, as the program reads it with solar disabled, received at
+    /// 1,700,000,000 seconds past the epoch.
+    fn reading() -> Reading {
+        let mut config = Config::default();
+        config.channels.insert("solar".to_string(), false);
+        let mut src = EventStream::new(&include_bytes!("../../tests/fixtures/live.xml")[..]);
+        let parser = config.parser().unwrap();
+        let (mut reading, errors) =
+            Reading::read(&mut src, &parser, &config, &mut HashMap::new()).unwrap();
+        assert!(errors.is_empty(), "{:?}", errors);
+        reading.received = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        reading
+    }
+
+    /// The samples in `body`, without the comments.
+    fn samples(body: &str) -> Vec<&str> {
+        body.lines().filter(|line| !line.starts_with('#')).collect()
+    }
+
+    #[test]
+    fn latest_reading() {
+        let body = render(Some(&reading()), None, "");
+        assert_eq!(samples(&body), [
+            "household_power_last_reading_timestamp_seconds 1700000000",
+            "household_power_watts{channel=\"hot_water\"} 2151",
+            "household_power_watts{channel=\"total\"} 345",
+            "household_power_temperature_celsius 18.7"
+        ]);
+        for name in ["household_power_last_reading_timestamp_seconds", "household_power_watts",
+                     "household_power_temperature_celsius"] {
+            assert!(body.contains(&format!("# TYPE {} gauge\n", name)), "{}", body);
+        }
+        assert_eq!(render(None, None, ""), "");
+    }
+
+    #[test]
+    fn stale_reading() {
+        let body = render(Some(&reading()), Some(Duration::from_secs(60)), "");
+        assert_eq!(samples(&body), ["household_power_last_reading_timestamp_seconds 1700000000"]);
+
+        let mut reading = reading();
+        reading.received = SystemTime::now() - Duration::from_secs(30);
+        let body = render(Some(&reading), Some(Duration::from_secs(60)), "");
+        assert_eq!(samples(&body).len(), 4, "{}", body);
+    }
+
+    /// Takes no readings until the sender is dropped.
+    struct Stuck(std::sync::mpsc::Receiver<()>);
+
+    impl Sink for Stuck {
+        fn send(&mut self, _: &Reading) -> Result<(), Error> {
+            let _ = self.0.recv();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn queues_and_parse_errors() {
+        let (unstick, stuck) = std::sync::mpsc::channel();
+        let mut sink = QueuedSink::new(Stuck(stuck), 1, Overflow::DropOldest,
+                                       Duration::from_secs(10));
+        let stats = sink.stats();
+        // The worker takes the first reading and gets stuck, the second waits, and the
+        // third pushes it out.
+        sink.send(&reading()).unwrap();
+        while stats.depth() > 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        for _ in 0..2 {
+            let _ = sink.send(&reading());
+        }
+
+        let parse_errors = ParseErrors::default();
+        parse_errors.error();
+        parse_errors.parsed();
+        parse_errors.error();
+        parse_errors.error();
+
+        let mut body = String::new();
+        let queues = [("festivus".to_string(), stats)];
+        render_queues(&mut body, &queues, "");
+        render_parse_errors(&mut body, &parse_errors, "");
+        assert_eq!(samples(&body), [
+            "household_power_queue_depth{backend=\"festivus\"} 1",
+            "household_power_queue_dropped_total{backend=\"festivus\"} 1",
+            "household_power_parse_errors_total 3",
+            "household_power_parse_errors_in_a_row 2"
+        ]);
+        assert!(body.contains("# TYPE household_power_queue_dropped_total counter\n"));
+        assert!(body.contains("# TYPE household_power_parse_errors_total counter\n"));
+
+        // Without queues, there are no queue metrics.
+        let mut body = String::new();
+        render_queues(&mut body, &[], "");
+        assert_eq!(body, "");
+
+        drop(unstick);
+    }
+
+    #[test]
+    fn healthz() {
+        let stale_after = Duration::from_secs(60);
+        assert_eq!(health(None, stale_after).0, "503 Service Unavailable");
+
+        let fields = HashMap::from([("total", "345".to_string())]);
+        let mut reading = Reading::from_fields(&fields, &[]).0;
+        assert_eq!(health(Some(&reading), stale_after), ("200 OK", "OK\n".to_string()));
+
+        reading.received -= Duration::from_secs(90);
+        assert_eq!(health(Some(&reading), stale_after),
+                   ("503 Service Unavailable", "Last reading 90s ago\n".to_string()));
+    }
+
+    #[test]
+    fn labels() {
+        let labels = BTreeMap::from([
+            ("location".to_string(), "shed \"B\"\nout the back".to_string()),
+            ("host".to_string(), "C:\\pi".to_string())
+        ]);
+        // By name, with backslashes, quotes and newlines escaped.
+        let labels = format_labels(&labels);
+        assert_eq!(labels, r#"host="C:\\pi",location="shed \"B\"\nout the back""#);
+        assert_eq!(format_labels(&BTreeMap::new()), "");
+
+        // Each metric's own label comes first.
+        let mut reading = reading();
+        reading.watts.insert("a\"b".to_string(), 1.0);
+        let body = render(Some(&reading), None, &labels);
+        assert_eq!(samples(&body), [
+            format!("household_power_last_reading_timestamp_seconds{{{}}} 1700000000", labels),
+            format!("household_power_watts{{channel=\"a\\\"b\",{}}} 1", labels),
+            format!("household_power_watts{{channel=\"hot_water\",{}}} 2151", labels),
+            format!("household_power_watts{{channel=\"total\",{}}} 345", labels),
+            format!("household_power_temperature_celsius{{{}}} 18.7", labels)
+        ]);
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::reading::Reading;
+use super::Sink;
+use super::prometheus::{format_labels, render};
+
+/// How long to wait for the Pushgateway to accept each push.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pushes the same metrics as the `PrometheusSink` serves to a Prometheus Pushgateway,
+/// for runs too short to be scraped.
+///
+/// The latest reading is pushed at most once every `interval`, and again when flushed,
+/// so that the last reading before exiting is always pushed. Each push replaces the
+/// metrics pushed before it.
+pub struct PushgatewaySink {
+    url: String,
+    /// The Pushgateway's host and port.
+    address: String,
+    /// The path to push to, for the job.
+    path: String,
+    /// The host and port of the HTTP proxy to push through, if any.
+    proxy: Option<String>,
+    interval: Duration,
+    /// The labels to put on every metric, as from `format_labels`.
+    labels: String,
+    user_agent: String,
+    last_push: Option<Instant>,
+    latest: Option<Reading>,
+    /// Whether the latest reading has been pushed yet.
+    pushed: bool
+}
+
+impl PushgatewaySink {
+    /// Push to the Pushgateway at `url`, like `http://localhost:9091`, as `job`, with
+    /// `labels` on every metric as well as their own, and `user_agent` on every request.
+    ///
+    /// Pushes go through the HTTP proxy `proxy`, like `http://proxy:3128`, or if that's
+    /// `None`, the one in `http_proxy` or `HTTP_PROXY`, unless `NO_PROXY` leaves out the
+    /// Pushgateway's host.
+    pub fn new(url: &str, job: &str, interval: Duration, labels: &BTreeMap<String, String>,
+               user_agent: &str, proxy: Option<&str>)
+        -> Result<PushgatewaySink, Error>
+    {
+        let (address, prefix) = split_url(url).ok_or_else(|| {
+            Error::Config(format!("Pushgateway URL {} must start with http://", url))
+        })?;
+        let proxy = proxy_for(&address, proxy, &|name| env::var(name).ok())?;
+        Ok(PushgatewaySink {
+            url: url.to_string(),
+            address,
+            path: format!("{}/metrics/job/{}", prefix.trim_end_matches('/'), job),
+            proxy,
+            interval,
+            labels: format_labels(labels),
+            user_agent: user_agent.to_string(),
+            last_push: None,
+            latest: None,
+            pushed: true
+        })
+    }
+
+    fn push(&mut self) -> Result<(), Error> {
+        self.last_push = Some(Instant::now());
+        self.pushed = true;
+        let body = render(self.latest.as_ref(), None, &self.labels);
+        self.put(&body).map_err(|e| match self.proxy {
+            Some(ref proxy) => {
+                Error::Backend(format!("Error pushing to {} through {}: {}", self.url, proxy, e))
+            }
+            None => Error::Backend(format!("Error pushing to {}: {}", self.url, e))
+        })
+    }
+
+    fn put(&self, body: &str) -> io::Result<()> {
+        // A proxy is sent the whole URL, rather than just the path.
+        let (connect_to, target) = match self.proxy {
+            Some(ref proxy) => (proxy, format!("http://{}{}", self.address, self.path)),
+            None => (&self.address, self.path.clone())
+        };
+        let address = connect_to.to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for host"))?;
+        let mut stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        write!(stream, "PUT {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: {}\r\n\
+                        Content-Type: text/plain; version=0.0.4\r\n\
+                        Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+               target, self.address, self.user_agent, body.len(), body)?;
+        stream.flush()?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            _ => Err(io::Error::other(format!("unexpected response {:?}", status_line.trim_end())))
+        }
+    }
+}
+
+impl Sink for PushgatewaySink {
+    fn send(&mut self, reading: &Reading) -> Result<(), Error> {
+        self.latest = Some(reading.clone());
+        self.pushed = false;
+        if self.last_push.is_none_or(|last_push| last_push.elapsed() >= self.interval) {
+            self.push()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if self.pushed {
+            return Ok(());
+        }
+        self.push()
+    }
+}
+
+/// The host and port in an `http://` URL, with port 80 if it doesn't say, and the path
+/// after them.
+fn split_url(url: &str) -> Option<(String, &str)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "")
+    };
+    // A port is optional, but an IPv6 address has colons of its own.
+    let has_port = authority.rsplit_once(':').is_some_and(|(_, port)| !port.contains(']'));
+    let address = if has_port {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+    Some((address, path))
+}
+
+/// The host and port of the proxy to connect to `address` through: `proxy` if it's given,
+/// or else the one in the environment, as looked up by `var`, unless `NO_PROXY` leaves
+/// `address` out. A proxy given without a scheme is taken to be `http://`.
+fn proxy_for(address: &str, proxy: Option<&str>, var: &dyn Fn(&str) -> Option<String>)
+    -> Result<Option<String>, Error>
+{
+    let proxy = match proxy.map(str::to_string).or_else(|| env_proxy(var)) {
+        Some(proxy) if !no_proxy(address, var) => proxy,
+        _ => return Ok(None)
+    };
+    let with_scheme = if proxy.contains("://") {
+        proxy.clone()
+    } else {
+        format!("http://{}", proxy)
+    };
+    let (proxy_address, _) = split_url(&with_scheme).ok_or_else(|| {
+        Error::Config(format!("Proxy {} must start with http://", proxy))
+    })?;
+    Ok(Some(proxy_address))
+}
+
+/// The proxy from the environment, preferring `http_proxy` as curl does.
+fn env_proxy(var: &dyn Fn(&str) -> Option<String>) -> Option<String> {
+    ["http_proxy", "HTTP_PROXY"].iter()
+        .filter_map(|name| var(name))
+        .find(|proxy| !proxy.is_empty())
+}
+
+/// Whether `NO_PROXY` says to connect to `address` directly. It lists hosts, each of
+/// which also covers its subdomains, or `*` for every host.
+fn no_proxy(address: &str, var: &dyn Fn(&str) -> Option<String>) -> bool {
+    let list = match ["no_proxy", "NO_PROXY"].iter().find_map(|name| var(name)) {
+        Some(list) => list,
+        None => return false
+    };
+    let host = host_of(address).to_ascii_lowercase();
+    list.split(',')
+        .map(|entry| host_of(entry.trim()).trim_start_matches('.').to_ascii_lowercase())
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| {
+            entry == "*" || host == entry ||
+                host.strip_suffix(&entry).is_some_and(|rest| rest.ends_with('.'))
+        })
+}
+
+/// The host in `host:port`, or `[::1]:port` for IPv6. The port is optional, so an IPv6
+/// address can also be bare, like `::1`.
+fn host_of(address: &str) -> &str {
+    let host = match address.rsplit_once(':') {
+        Some((host, _)) if !host.contains(':') || host.ends_with(']') => host,
+        _ => address
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /// Looks variables up in `vars` rather than the environment.
+    fn env<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        let vars: HashMap<_, _> = vars.iter().cloned().collect();
+        move |name| vars.get(name).map(|value| value.to_string())
+    }
+
+    #[test]
+    fn urls() {
+        assert_eq!(split_url("http://localhost:9091"), Some(("localhost:9091".into(), "")));
+        assert_eq!(split_url("http://pushgateway/prefix/"),
+                   Some(("pushgateway:80".into(), "/prefix/")));
+        assert_eq!(split_url("http://[::1]/"), Some(("[::1]:80".into(), "/")));
+        assert_eq!(split_url("http://[::1]:9091"), Some(("[::1]:9091".into(), "")));
+        assert_eq!(split_url("https://pushgateway"), None);
+    }
+
+    #[test]
+    fn no_proxy_hosts() {
+        let skipped = |address, list| no_proxy(address, &env(&[("NO_PROXY", list)]));
+        assert!(skipped("pushgateway:9091", "pushgateway"));
+        assert!(skipped("pushgateway:9091", "other, PushGateway"));
+        assert!(!skipped("pushgateway:9091", "pushgate"));
+        // Subdomains, with or without a leading dot.
+        assert!(skipped("push.example.com:80", "example.com"));
+        assert!(skipped("push.example.com:80", ".example.com"));
+        assert!(!skipped("pushexample.com:80", "example.com"));
+        assert!(skipped("anywhere:80", "*"));
+        // Ports in the list are ignored.
+        assert!(skipped("pushgateway:9091", "pushgateway:3128"));
+        assert!(skipped("[::1]:9091", "::1"));
+        assert!(skipped("[::1]:9091", "[::1]:9091"));
+        assert!(!skipped("[::2]:9091", "::1"));
+        assert!(!no_proxy("pushgateway:9091", &env(&[])));
+        assert!(no_proxy("pushgateway:9091", &env(&[("no_proxy", "pushgateway")])));
+    }
+
+    #[test]
+    fn proxies() {
+        let proxy = |given, vars| proxy_for("pushgateway:9091", given, &env(vars)).unwrap();
+        assert_eq!(proxy(None, &[]), None);
+        assert_eq!(proxy(None, &[("HTTP_PROXY", "http://upper:3128")]),
+                   Some("upper:3128".into()));
+        assert_eq!(proxy(None, &[("http_proxy", "lower:3128"), ("HTTP_PROXY", "upper:3128")]),
+                   Some("lower:3128".into()));
+        // An empty one is skipped, as curl does.
+        assert_eq!(proxy(None, &[("http_proxy", ""), ("HTTP_PROXY", "upper:3128")]),
+                   Some("upper:3128".into()));
+        assert_eq!(proxy(Some("http://given"), &[("http_proxy", "lower:3128")]),
+                   Some("given:80".into()));
+        assert_eq!(proxy(Some("given:3128"), &[("NO_PROXY", "pushgateway")]), None);
+        assert!(proxy_for("pushgateway:9091", Some("https://given"), &env(&[])).is_err());
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::reading::Reading;
+use super::Sink;
+
+/// What to do with a new reading when the queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// Drop the oldest queued reading to make room.
+    DropOldest,
+    /// Wait for the backend to catch up, holding up the parser.
+    Block
+}
+
+enum Message {
+    Reading(Box<Reading>),
+    Flush
+}
+
+struct Queue {
+    messages: VecDeque<Message>,
+    /// Errors from the worker, waiting to be returned by the next send.
+    errors: Vec<String>,
+    /// Readings dropped so far because the queue was full.
+    dropped: u64,
+    /// Whether the worker should stop once the queue is empty.
+    closed: bool,
+    /// Whether the worker has stopped, having emptied the queue or panicked.
+    finished: bool
+}
+
+impl Queue {
+    /// How many readings are queued, not counting flushes.
+    fn readings(&self) -> usize {
+        self.messages.iter().filter(|message| matches!(message, Message::Reading(_))).count()
+    }
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    /// Signalled whenever a message is added to or taken from the queue.
+    changed: Condvar
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        // A panic in the sink only kills the worker, and leaves the queue itself intact.
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn wait<'a>(&self, guard: MutexGuard<'a, Queue>) -> MutexGuard<'a, Queue> {
+        self.changed.wait(guard).unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn wait_timeout<'a>(&self, guard: MutexGuard<'a, Queue>, timeout: Duration)
+        -> MutexGuard<'a, Queue>
+    {
+        match self.changed.wait_timeout(guard, timeout) {
+            Ok((guard, _)) => guard,
+            Err(e) => e.into_inner().0
+        }
+    }
+}
+
+/// Marks the worker as finished when it returns or panics.
+struct Finished<'a>(&'a Shared);
+
+impl<'a> Drop for Finished<'a> {
+    fn drop(&mut self) {
+        self.0.lock().finished = true;
+        self.0.changed.notify_all();
+    }
+}
+
+/// Sends readings to a sink from a separate thread, through a bounded queue, so that a
+/// slow backend doesn't hold up reading from the serial port.
+///
+/// Errors from the sink are returned by the next call to `send` or `flush`. Dropping the
+/// `QueuedSink` waits up to `drain_timeout` for everything queued to be sent.
+pub struct QueuedSink {
+    shared: Arc<Shared>,
+    capacity: usize,
+    overflow: Overflow,
+    drain_timeout: Duration,
+    worker: Option<JoinHandle<()>>
+}
+
+impl QueuedSink {
+    /// Start a worker thread sending to `sink`, queueing up to `capacity` readings (at
+    /// least one).
+    pub fn new<S: Sink + Send + 'static>(sink: S, capacity: usize, overflow: Overflow,
+                                         drain_timeout: Duration)
+        -> QueuedSink
+    {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                messages: VecDeque::new(),
+                errors: vec![],
+                dropped: 0,
+                closed: false,
+                finished: false
+            }),
+            changed: Condvar::new()
+        });
+        let worker_shared = shared.clone();
+        let worker = thread::spawn(move || run_worker(sink, &worker_shared));
+        QueuedSink {
+            shared,
+            capacity: capacity.max(1),
+            overflow,
+            drain_timeout,
+            worker: Some(worker)
+        }
+    }
+
+    /// A handle for watching the queue from elsewhere, as for metrics.
+    pub fn stats(&self) -> QueueStats {
+        QueueStats { shared: self.shared.clone() }
+    }
+
+    /// Take the errors from the worker, as one.
+    fn take_errors(queue: &mut Queue) -> Result<(), Error> {
+        if queue.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Backend(queue.errors.drain(..).collect::<Vec<_>>().join("; ")))
+        }
+    }
+}
+
+/// How far behind a `QueuedSink` is, and what it's had to drop, for showing whether sending
+/// keeps up with reading.
+#[derive(Clone)]
+pub struct QueueStats {
+    shared: Arc<Shared>
+}
+
+impl QueueStats {
+    /// Readings waiting to be sent.
+    pub fn depth(&self) -> usize {
+        self.shared.lock().readings()
+    }
+
+    /// Readings dropped so far because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.shared.lock().dropped
+    }
+}
+
+fn run_worker<S: Sink>(mut sink: S, shared: &Shared) {
+    let _finished = Finished(shared);
+    loop {
+        let message = {
+            let mut queue = shared.lock();
+            loop {
+                if let Some(message) = queue.messages.pop_front() {
+                    break message;
+                }
+                if queue.closed {
+                    drop(queue);
+                    if let Err(e) = sink.flush() {
+                        error!("{}", e);
+                    }
+                    return;
+                }
+                queue = shared.wait(queue);
+            }
+        };
+        shared.changed.notify_all();
+
+        let result = match message {
+            Message::Reading(reading) => sink.send(&reading),
+            Message::Flush => sink.flush()
+        };
+        if let Err(e) = result {
+            shared.lock().errors.push(e.to_string());
+        }
+    }
+}
+
+impl Sink for QueuedSink {
+    fn send(&mut self, reading: &Reading) -> Result<(), Error> {
+        let mut queue = self.shared.lock();
+        if queue.finished {
+            return Err(Error::Backend("Backend thread stopped".into()));
+        }
+        let mut dropped = false;
+        // Flushes are left in the queue, and don't count towards its capacity.
+        if queue.readings() >= self.capacity {
+            match self.overflow {
+                Overflow::DropOldest => {
+                    let oldest = queue.messages.iter()
+                        .position(|message| matches!(message, Message::Reading(_)));
+                    if let Some(oldest) = oldest {
+                        queue.messages.remove(oldest);
+                    }
+                    queue.dropped += 1;
+                    dropped = true;
+                }
+                Overflow::Block => {
+                    warn!("Queue full, waiting for the backend to catch up");
+                    while queue.readings() >= self.capacity && !queue.finished {
+                        queue = self.shared.wait(queue);
+                    }
+                }
+            }
+        }
+        queue.messages.push_back(Message::Reading(Box::new(reading.clone())));
+        self.shared.changed.notify_all();
+
+        if dropped {
+            let message = format!("Queue full, dropped the oldest reading ({} dropped so far)",
+                                  queue.dropped);
+            queue.errors.push(message);
+        }
+        QueuedSink::take_errors(&mut queue)
+    }
+
+    /// Ask the worker to flush the sink, once it has sent everything queued so far.
+    fn flush(&mut self) -> Result<(), Error> {
+        let mut queue = self.shared.lock();
+        queue.messages.push_back(Message::Flush);
+        self.shared.changed.notify_all();
+        QueuedSink::take_errors(&mut queue)
+    }
+}
+
+impl Drop for QueuedSink {
+    fn drop(&mut self) {
+        let deadline = Instant::now() + self.drain_timeout;
+        let mut queue = self.shared.lock();
+        queue.closed = true;
+        self.shared.changed.notify_all();
+        while !queue.finished {
+            let now = Instant::now();
+            if now >= deadline {
+                // Leave the worker to be killed on exit.
+                warn!("Gave up waiting for a backend, with {} readings unsent",
+                      queue.readings());
+                return;
+            }
+            queue = self.shared.wait_timeout(queue, deadline - now);
+        }
+        drop(queue);
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                error!("Backend thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::mpsc::{self, Receiver};
+
+    use super::*;
+
+    /// Records what it's sent, waiting for the go-ahead before taking each reading.
+    struct Recording {
+        go: Receiver<()>,
+        sent: Arc<Mutex<Vec<String>>>
+    }
+
+    impl Sink for Recording {
+        fn send(&mut self, reading: &Reading) -> Result<(), Error> {
+            self.go.recv().unwrap();
+            self.sent.lock().unwrap().push(format!("{}", reading.watts["total"]));
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            self.sent.lock().unwrap().push("flush".into());
+            Ok(())
+        }
+    }
+
+    fn reading(total: &str) -> Reading {
+        Reading::from_fields(&HashMap::from([("total", total.to_string())]), &[]).0
+    }
+
+    #[test]
+    fn drop_oldest_keeps_flushes() {
+        let (go, wait) = mpsc::channel();
+        let sent = Arc::new(Mutex::new(vec![]));
+        let mut sink = QueuedSink::new(Recording { go: wait, sent: sent.clone() }, 2,
+                                       Overflow::DropOldest, Duration::from_secs(10));
+        let stats = sink.stats();
+
+        // The worker takes the first reading, and waits with it.
+        sink.send(&reading("1")).unwrap();
+        while stats.depth() > 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        sink.flush().unwrap();
+        sink.send(&reading("2")).unwrap();
+        sink.send(&reading("3")).unwrap();
+        assert!(sink.send(&reading("4")).is_err());
+        assert_eq!((stats.depth(), stats.dropped()), (2, 1));
+
+        for _ in 0..3 {
+            go.send(()).unwrap();
+        }
+        drop(sink);
+        // The flush queued before the dropped reading is kept, and the sink is flushed
+        // again when it is dropped.
+        assert_eq!(*sent.lock().unwrap(), ["1", "flush", "3", "4", "flush"]);
+    }
+}
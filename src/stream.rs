@@ -0,0 +1,117 @@
+//! Readings as an async `Stream`, for use from a tokio runtime.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use tokio::sync::mpsc;
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::parser::{EventStream, Parser};
+use crate::reading::{Pipeline, Reading};
+
+/// Readings from a source like a serial port, read on a blocking task of their own.
+///
+/// Bad messages are yielded as errors, and reading carries on with the next one, unless
+/// there have been `parse_errors.max` of them in a row. The stream ends then too, as after
+/// an error reading the source itself, like the serial port going away or timing out, so
+/// that the caller can reopen it. Fields with bad values are logged, and left out of the
+/// reading.
+///
+/// Readings go through the same `Pipeline` as in the program, so repeats from a sensor are
+/// dropped if there's a `sensor_key`, and deltas and overproduction are worked out.
+///
+/// Once `capacity` readings (at least one) are waiting, reading waits for the consumer to
+/// catch up, as with a queue that has `overflow = "block"`. Anything the source sends
+/// meanwhile is left to its own buffering, which for a serial port may drop it. Dropping
+/// the stream stops reading after the next message.
+pub struct ReadingStream {
+    receiver: mpsc::Receiver<Result<Reading, Error>>
+}
+
+impl ReadingStream {
+    /// Start reading from `source`, parsing its messages with the parser from
+    /// `Config::parser`, and converting them as the config says.
+    ///
+    /// This must be called from within a tokio runtime. The config and parser are shared,
+    /// so that a new stream can be started with them after reopening the source.
+    pub fn spawn<T: Read + Send + 'static>(source: T, config: Arc<Config>, parser: Arc<Parser>,
+                                           capacity: usize)
+        -> ReadingStream
+    {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+
+        tokio::task::spawn_blocking(move || {
+            let mut src = EventStream::new(source);
+            if config.archive.is_some() {
+                src.keep_raw();
+            }
+            if config.passthrough {
+                src.capture_unknown();
+            }
+            let mut data = HashMap::new();
+            let mut pipeline = Pipeline::new(&config);
+            let mut parse_errors = 0;
+            loop {
+                let result = Reading::read(&mut src, &parser, &config, &mut data)
+                    .map(|(mut reading, errors)| {
+                        for e in errors {
+                            warn!("{}", e);
+                        }
+                        let applied = pipeline.apply(&mut reading);
+                        if let Some(firmware) = applied.firmware {
+                            info!("Monitor firmware {}", firmware);
+                        }
+                        if let Some((sensor, time)) = applied.repeat {
+                            warn!("Dropped a repeated reading from sensor {} at {}", sensor, time);
+                            return None;
+                        }
+                        if let Some(exporting) = applied.exporting {
+                            let change = if exporting { "Started" } else { "Stopped" };
+                            info!("{} exporting solar", change);
+                        }
+                        Some(reading)
+                    });
+                let fatal = match result {
+                    Ok(_) => {
+                        parse_errors = 0;
+                        false
+                    }
+                    Err(Error::Parse(_)) | Err(Error::Xml(_)) => {
+                        parse_errors += 1;
+                        config.parse_errors.max.is_some_and(|max| parse_errors >= max)
+                    }
+                    Err(_) => true
+                };
+                // Repeats are dropped, having been logged.
+                let result = match result.transpose() {
+                    Some(result) => result,
+                    None => continue
+                };
+                // Stop once the stream has been dropped.
+                if sender.blocking_send(result).is_err() || fatal {
+                    return;
+                }
+            }
+        });
+
+        ReadingStream { receiver }
+    }
+
+    /// The next reading, or `None` once the stream has ended.
+    pub async fn next(&mut self) -> Option<Result<Reading, Error>> {
+        self.receiver.recv().await
+    }
+}
+
+impl Stream for ReadingStream {
+    type Item = Result<Reading, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
@@ -0,0 +1,398 @@
+//! Daily summaries of energy use, built up one reading at a time.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Local, NaiveDate, NaiveTime, TimeZone, Utc};
+use serde::Serialize;
+
+use crate::config::Units;
+use crate::error::Error;
+use crate::reading::{Reading, round};
+
+/// Readings further apart than this aren't counted towards energy use, as the sensor was
+/// probably away in between.
+const MAX_GAP: Duration = Duration::from_secs(5 * 60);
+
+/// The local date a reading was taken on.
+pub fn date_of(reading: &Reading) -> NaiveDate {
+    date_in(reading, &Local)
+}
+
+/// The date a reading was taken on, in `zone`.
+pub fn date_in<Tz: TimeZone>(reading: &Reading, zone: &Tz) -> NaiveDate {
+    DateTime::<Utc>::from(reading.timestamp).with_timezone(zone).date_naive()
+}
+
+/// Summarise a day of readings, all from the same local date, or `None` if there aren't
+/// any.
+pub fn summarise<'a, I>(readings: I, price_per_kwh: Option<f64>) -> Option<Report>
+    where I: IntoIterator<Item = &'a Reading>
+{
+    summarise_in(readings, price_per_kwh, Local)
+}
+
+/// Summarise a day of readings, all from the same date in `zone`, or `None` if there
+/// aren't any.
+pub fn summarise_in<'a, I, Tz>(readings: I, price_per_kwh: Option<f64>, zone: Tz)
+    -> Option<Report>
+    where I: IntoIterator<Item = &'a Reading>, Tz: TimeZone, Tz::Offset: fmt::Display
+{
+    let mut readings = readings.into_iter();
+    let first = readings.next()?;
+    let mut summary = DaySummary::new_in(date_in(first, &zone), zone);
+    summary.add(first);
+    for reading in readings {
+        summary.add(reading);
+    }
+    Some(summary.report(price_per_kwh))
+}
+
+/// Running totals for one day.
+///
+/// Each channel's energy use is its watts held from one reading until the next, so the
+/// day runs up to its last reading, or up to midnight once it's rolled over to the next.
+/// Days are local ones, unless they're in another time zone.
+pub struct DaySummary<Tz: TimeZone = Local> {
+    zone: Tz,
+    date: NaiveDate,
+    readings: u64,
+    channels: BTreeMap<String, ChannelTotals>,
+    /// The last reading's watts, and when they've been held since: when it was taken, or
+    /// midnight for the watts carried over from the day before.
+    last: Option<(SystemTime, BTreeMap<String, f64>)>
+}
+
+struct ChannelTotals {
+    watt_hours: f64,
+    peak: (f64, SystemTime),
+    min: f64,
+    sum: f64,
+    count: u64
+}
+
+impl DaySummary {
+    pub fn new(date: NaiveDate) -> DaySummary {
+        DaySummary::new_in(date, Local)
+    }
+}
+
+impl<Tz: TimeZone> DaySummary<Tz> where Tz::Offset: fmt::Display {
+    /// A summary of a day in `zone`.
+    pub fn new_in(date: NaiveDate, zone: Tz) -> DaySummary<Tz> {
+        DaySummary {
+            zone,
+            date,
+            readings: 0,
+            channels: BTreeMap::new(),
+            last: None
+        }
+    }
+
+    pub fn date(&self) -> NaiveDate {
+        self.date
+    }
+
+    pub fn add(&mut self, reading: &Reading) {
+        for (channel, &watts) in &reading.watts {
+            let totals = self.channels.entry(channel.clone()).or_insert(ChannelTotals {
+                watt_hours: 0.0,
+                peak: (watts, reading.timestamp),
+                min: watts,
+                sum: 0.0,
+                count: 0
+            });
+            if watts > totals.peak.0 {
+                totals.peak = (watts, reading.timestamp);
+            }
+            totals.min = totals.min.min(watts);
+            totals.sum += watts;
+            totals.count += 1;
+        }
+        self.hold(reading.timestamp);
+        self.readings += 1;
+        self.last = Some((reading.timestamp, reading.watts.clone()));
+    }
+
+    /// End the day at the midnight before `next`, the first reading of a later day, and
+    /// start that day's summary. The watts from the last reading are split between the two
+    /// at midnight, unless the sensor was away for too long in between for either.
+    pub fn roll_over(&mut self, next: &Reading) -> DaySummary<Tz> {
+        let date = date_in(next, &self.zone);
+        let mut summary = DaySummary::new_in(date, self.zone.clone());
+        let (last_timestamp, last_watts) = match self.last {
+            Some((timestamp, ref watts)) => (timestamp, watts.clone()),
+            None => return summary
+        };
+        let gap = next.timestamp.duration_since(last_timestamp).unwrap_or(MAX_GAP);
+        if gap >= MAX_GAP {
+            return summary;
+        }
+        // Midnight can be skipped by a DST change, leaving the day to run up to `next`.
+        let midnight = self.zone.from_local_datetime(&date.and_time(NaiveTime::MIN)).earliest()
+            .map(SystemTime::from)
+            .map_or(next.timestamp, |midnight| midnight.clamp(last_timestamp, next.timestamp));
+        self.hold(midnight);
+        summary.last = Some((midnight, last_watts));
+        summary
+    }
+
+    /// Count the energy use of the last reading's watts, held until `until`, unless the
+    /// sensor was probably away in between.
+    fn hold(&mut self, until: SystemTime) {
+        if let Some((since, ref watts)) = self.last {
+            let gap = until.duration_since(since).unwrap_or(MAX_GAP);
+            if gap < MAX_GAP {
+                let hours = gap.as_secs_f64() / 3600.0;
+                for (channel, watts) in watts {
+                    if let Some(totals) = self.channels.get_mut(channel) {
+                        totals.watt_hours += watts * hours;
+                    }
+                }
+            }
+        }
+    }
+
+    /// The summary so far, costing the `total` channel at `price_per_kwh` if it's given.
+    pub fn report(&self, price_per_kwh: Option<f64>) -> Report {
+        let channels: BTreeMap<String, ChannelReport> = self.channels.iter()
+            .map(|(channel, totals)| {
+                let peak_at = DateTime::<Utc>::from(totals.peak.1).with_timezone(&self.zone)
+                    .format("%H:%M:%S");
+                (channel.clone(), ChannelReport {
+                    kwh: round(totals.watt_hours / 1000.0, 3),
+                    peak_watts: totals.peak.0,
+                    peak_at: peak_at.to_string(),
+                    min_watts: totals.min,
+                    average_watts: round(totals.sum / totals.count as f64, 0)
+                })
+            })
+            .collect();
+        let cost = price_per_kwh
+            .and_then(|price| channels.get("total").map(|total| round(total.kwh * price, 2)));
+        Report {
+            date: self.date.to_string(),
+            readings: self.readings,
+            cost,
+            channels
+        }
+    }
+}
+
+/// A summary of one day's readings.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    /// The local date, as `YYYY-MM-DD`.
+    pub date: String,
+    pub readings: u64,
+    /// The cost of the `total` channel's energy use, to 2 decimal places, if a price was
+    /// given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+    pub channels: BTreeMap<String, ChannelReport>
+}
+
+/// One channel's part of the report. The kWh are rounded to 3 decimal places, and the
+/// average to whole watts.
+#[derive(Debug, Serialize)]
+pub struct ChannelReport {
+    pub kwh: f64,
+    pub peak_watts: f64,
+    /// The local time of the peak, as `HH:MM:SS`.
+    pub peak_at: String,
+    pub min_watts: f64,
+    /// The average of the readings, in watts.
+    pub average_watts: f64
+}
+
+impl Report {
+    /// Append the report to a file, as a line of JSON.
+    pub fn append_to(&self, path: &Path) -> Result<(), Error> {
+        let write = || -> Result<(), Box<dyn std::error::Error>> {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            let mut line = serde_json::to_vec(self)?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+            Ok(())
+        };
+        write().map_err(|e| Error::Backend(format!("Error writing summary to {}: {}",
+                                                   path.display(), e)))
+    }
+
+    /// The report on one line, as by `Display`, but in `units`, like
+    /// `summary ... cost=$3.09 total_mj=44.442 ...`.
+    pub fn line<'a>(&'a self, units: &'a Units) -> ReportLine<'a> {
+        ReportLine { report: self, units }
+    }
+}
+
+/// Shows the report on one line, like
+/// `summary date=2024-01-31 readings=14400 total_kwh=12.345 total_peak_watts=3456 ...`.
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.line(&Units::default()).fmt(f)
+    }
+}
+
+/// A report shown in chosen units, from `Report::line`.
+pub struct ReportLine<'a> {
+    report: &'a Report,
+    units: &'a Units
+}
+
+impl<'a> fmt::Display for ReportLine<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let report = self.report;
+        write!(f, "summary date={} readings={}", report.date, report.readings)?;
+        if let Some(cost) = report.cost {
+            write!(f, " cost={}", self.units.cost(cost))?;
+        }
+        for (channel, channel_report) in &report.channels {
+            let (energy, unit) = self.units.energy(channel_report.kwh);
+            write!(f, " {c}_{}={:.3} {c}_peak_watts={} {c}_peak_at={} {c}_min_watts={} \
+                       {c}_average_watts={:.0}",
+                   unit, energy, channel_report.peak_watts, channel_report.peak_at,
+                   channel_report.min_watts, channel_report.average_watts, c = channel)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono_tz::Tz;
+    use chrono_tz::America::Santiago;
+    use chrono_tz::Australia::Sydney;
+    use chrono_tz::Europe::London;
+
+    use super::*;
+
+    fn reading(at: DateTime<Tz>, total: f64) -> Reading {
+        let fields = HashMap::from([("total", total.to_string())]);
+        let mut reading = Reading::from_fields(&fields, &[]).0;
+        reading.timestamp = at.into();
+        reading
+    }
+
+    /// A reading at a time on the 31st of January in Sydney.
+    fn at(time: (u32, u32, u32), total: f64) -> Reading {
+        reading(Sydney.with_ymd_and_hms(2024, 1, 31, time.0, time.1, time.2).unwrap(), total)
+    }
+
+    /// The summary of each day the readings are on, rolling over from one to the next as
+    /// the program does.
+    fn days(zone: Tz, readings: &[Reading]) -> Vec<Report> {
+        let mut days: Vec<DaySummary<Tz>> = vec![];
+        for reading in readings {
+            let date = date_in(reading, &zone);
+            let next_day = match days.last_mut() {
+                Some(day) if day.date() == date => None,
+                Some(day) => Some(day.roll_over(reading)),
+                None => Some(DaySummary::new_in(date, zone))
+            };
+            days.extend(next_day);
+            days.last_mut().unwrap().add(reading);
+        }
+        days.iter().map(|day| day.report(None)).collect()
+    }
+
+    /// A reading of 1 kW every 4 minutes, from `start` until `end`.
+    fn every_4_minutes(start: DateTime<Tz>, end: DateTime<Tz>) -> Vec<Reading> {
+        let mut readings = vec![];
+        let mut at = start;
+        while at <= end {
+            readings.push(reading(at, 1000.0));
+            at += chrono::Duration::minutes(4);
+        }
+        readings
+    }
+
+    #[test]
+    fn totals() {
+        let readings = [at((12, 0, 0), 1000.0), at((12, 3, 0), 3000.0), at((12, 6, 0), 2000.0),
+                        at((12, 9, 0), 2000.0)];
+        let report = summarise_in(&readings, Some(0.5), Sydney).unwrap();
+        assert_eq!(report.readings, 4);
+        let total = &report.channels["total"];
+        // Each reading's watts count for the 3 minutes until the next, and the last's don't.
+        assert_eq!(total.kwh, 0.3);
+        assert_eq!((total.peak_watts, &total.peak_at[..]), (3000.0, "12:03:00"));
+        assert_eq!(total.min_watts, 1000.0);
+        assert_eq!(total.average_watts, 2000.0);
+        assert_eq!(report.cost, Some(0.15));
+        assert_eq!(summarise_in(&readings, None, Sydney).unwrap().cost, None);
+    }
+
+    #[test]
+    fn max_gap() {
+        // The sensor was away for 10 minutes after the first reading.
+        let readings = [at((12, 0, 0), 1000.0), at((12, 10, 0), 2000.0), at((12, 13, 0), 2000.0)];
+        assert_eq!(summarise_in(&readings, None, Sydney).unwrap().channels["total"].kwh, 0.1);
+    }
+
+    #[test]
+    fn roll_over_at_midnight() {
+        let on_1st = |h, m| Sydney.with_ymd_and_hms(2024, 2, 1, h, m, 0).unwrap();
+        let readings = [at((23, 58, 0), 1200.0), reading(on_1st(0, 2), 600.0),
+                        reading(on_1st(0, 5), 600.0)];
+        let days = days(Sydney, &readings);
+        assert_eq!(days.len(), 2);
+        assert_eq!((days[0].readings, days[0].channels["total"].kwh), (1, 0.04));
+        assert_eq!(days[1].date, "2024-02-01");
+        assert_eq!((days[1].readings, days[1].channels["total"].kwh), (2, 0.07));
+    }
+
+    #[test]
+    fn roll_over_after_a_gap() {
+        let readings = [at((23, 55, 0), 1200.0),
+                        reading(Sydney.with_ymd_and_hms(2024, 2, 1, 0, 5, 0).unwrap(), 600.0)];
+        let days = days(Sydney, &readings);
+        assert_eq!(days[0].channels["total"].kwh, 0.0);
+        assert_eq!(days[1].channels["total"].kwh, 0.0);
+    }
+
+    /// The clocks go forward from 01:00 to 02:00 on the 31st of March.
+    #[test]
+    fn spring_forward() {
+        let start = London.with_ymd_and_hms(2024, 3, 30, 23, 0, 0).unwrap();
+        let end = London.with_ymd_and_hms(2024, 4, 1, 1, 0, 0).unwrap();
+        let days = days(London, &every_4_minutes(start, end));
+        let days: Vec<_> = days.iter()
+            .map(|day| (&day.date[..], day.readings, day.channels["total"].kwh))
+            .collect();
+        assert_eq!(days, [("2024-03-30", 15, 1.0), ("2024-03-31", 23 * 15, 23.0),
+                          ("2024-04-01", 16, 1.0)]);
+    }
+
+    /// The clocks go back from 02:00 to 01:00 on the 27th of October, so that 01:00 to 02:00
+    /// happens twice, and is counted both times, but only once each.
+    #[test]
+    fn fall_back() {
+        let start = London.with_ymd_and_hms(2024, 10, 26, 23, 0, 0).unwrap();
+        let end = London.with_ymd_and_hms(2024, 10, 28, 1, 0, 0).unwrap();
+        let days = days(London, &every_4_minutes(start, end));
+        let days: Vec<_> = days.iter()
+            .map(|day| (&day.date[..], day.readings, day.channels["total"].kwh))
+            .collect();
+        assert_eq!(days, [("2024-10-26", 15, 1.0), ("2024-10-27", 25 * 15, 25.0),
+                          ("2024-10-28", 16, 1.0)]);
+    }
+
+    /// In Santiago, the clocks go forward from midnight to 01:00 on the 8th of September, so
+    /// there's no midnight to split at, and the day before runs up to the next reading.
+    #[test]
+    fn midnight_skipped() {
+        let readings = [reading(Santiago.with_ymd_and_hms(2024, 9, 7, 23, 58, 0).unwrap(), 1200.0),
+                        reading(Santiago.with_ymd_and_hms(2024, 9, 8, 1, 2, 0).unwrap(), 600.0)];
+        let days = days(Santiago, &readings);
+        assert_eq!(days.len(), 2);
+        assert_eq!((&days[1].date[..], days[0].channels["total"].kwh), ("2024-09-08", 0.08));
+        assert_eq!(days[1].channels["total"].kwh, 0.0);
+    }
+}
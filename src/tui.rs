@@ -0,0 +1,177 @@
+//! A live dashboard in the terminal, drawn from the latest readings.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use ratatui::Frame;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::symbols::Marker;
+use ratatui::text::Line;
+use ratatui::widgets::{Axis, Block, Chart, Dataset, GraphType, Paragraph};
+
+use crate::config::Units;
+use crate::reading::Reading;
+
+/// How much history the chart shows.
+const WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// How often to redraw, and check for key presses.
+const TICK: Duration = Duration::from_millis(250);
+
+const COLORS: [Color; 6] =
+    [Color::Green, Color::Yellow, Color::Cyan, Color::Magenta, Color::Blue, Color::Red];
+
+/// Everything the dashboard shows, updated by the reading loop as it goes.
+pub struct Dashboard {
+    started: Instant,
+    latest: Option<Reading>,
+    /// Recent watts for each channel, as seconds since `started`.
+    history: BTreeMap<String, Vec<(f64, f64)>>,
+    readings: u64,
+    errors: u64,
+    last_message: Option<String>,
+    /// The units to show the temperature in.
+    units: Units
+}
+
+impl Default for Dashboard {
+    fn default() -> Dashboard {
+        Dashboard::new(&Units::default())
+    }
+}
+
+impl Dashboard {
+    pub fn new(units: &Units) -> Dashboard {
+        Dashboard {
+            started: Instant::now(),
+            latest: None,
+            history: BTreeMap::new(),
+            readings: 0,
+            errors: 0,
+            last_message: None,
+            units: units.clone()
+        }
+    }
+
+    pub fn reading(&mut self, reading: &Reading) {
+        let now = self.started.elapsed().as_secs_f64();
+        for (channel, &watts) in &reading.watts {
+            let points = self.history.entry(channel.clone()).or_default();
+            points.push((now, watts));
+            let old = points.iter().take_while(|&&(t, _)| t < now - WINDOW.as_secs_f64()).count();
+            points.drain(..old);
+        }
+        self.latest = Some(reading.clone());
+        self.readings += 1;
+    }
+
+    /// Show a message in the status bar, counting it as an error if it is one.
+    pub fn message(&mut self, message: String, error: bool) {
+        if error {
+            self.errors += 1;
+        }
+        self.last_message = Some(message);
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [chart_area, values_area, status_area] = Layout::vertical([
+            Constraint::Min(8),
+            Constraint::Length(3),
+            Constraint::Length(1)
+        ]).areas(frame.area());
+
+        let now = self.started.elapsed().as_secs_f64();
+        let start = (now - WINDOW.as_secs_f64()).max(0.0);
+        let max_watts = self.history.values()
+            .flat_map(|points| points.iter().map(|&(_, watts)| watts))
+            .fold(100.0, f64::max);
+        let datasets = self.history.iter().zip(COLORS.iter().cycle())
+            .map(|((channel, points), &color)| {
+                Dataset::default()
+                    .name(channel.clone())
+                    .marker(Marker::Braille)
+                    .graph_type(GraphType::Line)
+                    .style(Style::default().fg(color))
+                    .data(points)
+            })
+            .collect();
+        let chart = Chart::new(datasets)
+            .block(Block::bordered().title("Power (W), last 10 minutes"))
+            .x_axis(Axis::default().bounds([start, now.max(1.0)]))
+            .y_axis(Axis::default()
+                .bounds([0.0, max_watts * 1.1])
+                .labels(["0".to_string(), format!("{:.0}", max_watts * 1.1)]));
+        frame.render_widget(chart, chart_area);
+
+        let mut values = vec![];
+        if let Some(ref reading) = self.latest {
+            if let Some(ref time) = reading.time {
+                values.push(format!("time {}", time));
+            }
+            if let Some(temperature) = reading.temperature {
+                values.push(format!("temperature {}", self.units.temperature(temperature)));
+            }
+            for (channel, watts) in &reading.watts {
+                values.push(format!("{} {} W", channel, watts));
+            }
+            if reading.low_battery == Some(true) {
+                values.push("battery low".to_string());
+            }
+            if let Ok(age) = SystemTime::now().duration_since(reading.received) {
+                values.push(format!("{}s ago", age.as_secs()));
+            }
+        } else {
+            values.push("Waiting for the first reading".to_string());
+        }
+        frame.render_widget(Paragraph::new(values.join("   ")).block(Block::bordered()),
+                            values_area);
+
+        let uptime = self.started.elapsed().as_secs();
+        let mut status = format!("up {}h{:02}m{:02}s   {} readings   {} errors   q to quit",
+                                 uptime / 3600, uptime / 60 % 60, uptime % 60,
+                                 self.readings, self.errors);
+        if let Some(ref message) = self.last_message {
+            status = format!("{}   {}", status, message);
+        }
+        frame.render_widget(Line::from(status), status_area);
+    }
+}
+
+/// Draw the dashboard until `q` is pressed or `shutdown` is set, setting `shutdown` on the
+/// way out.
+///
+/// The terminal is restored before returning, whatever happens.
+pub fn run(dashboard: &Mutex<Dashboard>, shutdown: &AtomicBool) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let result = (|| {
+        while !shutdown.load(Ordering::SeqCst) {
+            {
+                let dashboard = dashboard.lock().unwrap_or_else(|e| e.into_inner());
+                terminal.draw(|frame| dashboard.draw(frame))?;
+            }
+            if !event::poll(TICK)? {
+                continue;
+            }
+            if let Event::Key(key) = event::read()? {
+                // Ctrl-C arrives as a key press, as the terminal is in raw mode.
+                let quit = key.kind == KeyEventKind::Press && match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => true,
+                    KeyCode::Char('c') => key.modifiers.contains(KeyModifiers::CONTROL),
+                    _ => false
+                };
+                if quit {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    })();
+    ratatui::restore();
+    shutdown.store(true, Ordering::SeqCst);
+    result
+}
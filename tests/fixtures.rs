@@ -0,0 +1,41 @@
+//! Snapshots of what the Envi parser reads from each message fixture.
+
+use std::collections::BTreeMap;
+
+use household_power::{Error, EventStream, envi_parser, run_parser};
+
+/// The result of parsing each message in `input`, up to the end of the stream, with the
+/// values sorted by key.
+fn parse(input: &[u8]) -> Vec<Result<BTreeMap<&'static str, String>, String>> {
+    let parser = envi_parser();
+    let mut src = EventStream::new(input);
+    let mut results = vec![];
+    loop {
+        match run_parser(&mut src, &parser) {
+            Err(Error::Serial(_)) => return results,
+            result => results.push(result.map(|values| values.into_iter().collect())
+                                         .map_err(|e| e.to_string()))
+        }
+    }
+}
+
+macro_rules! fixture_snapshot {
+    ($name:ident) => {
+        #[test]
+        fn $name() {
+            let input = include_bytes!(concat!("fixtures/", stringify!($name), ".xml"));
+            insta::assert_debug_snapshot!(parse(input));
+        }
+    };
+}
+
+fixture_snapshot!(live);
+fixture_snapshot!(live_no_temperature);
+fixture_snapshot!(live_decimal);
+fixture_snapshot!(live_attributes);
+fixture_snapshot!(live_empty_tags);
+fixture_snapshot!(live_cdata);
+fixture_snapshot!(live_indented);
+fixture_snapshot!(history);
+// Cut short in a tag's name and in its text, with line noise between messages.
+fixture_snapshot!(corrupt);
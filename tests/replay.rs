@@ -0,0 +1,79 @@
+//! Replaying a capture from the serial port through everything between it and the sinks.
+
+use household_power::sink::Sink;
+use household_power::{Config, Error, EventStream, Pipeline, Reading};
+
+/// Keeps every reading it's sent.
+#[derive(Default)]
+struct Recording {
+    readings: Vec<Reading>
+}
+
+impl Sink for Recording {
+    fn send(&mut self, reading: &Reading) -> Result<(), Error> {
+        self.readings.push(reading.clone());
+        Ok(())
+    }
+}
+
+/// Read every message in `capture`, as the program does, sending the readings to `sink`
+/// and returning the errors for the messages that couldn't be read.
+fn replay(capture: &[u8], config: &Config, sink: &mut dyn Sink) -> Vec<String> {
+    let parser = config.parser().unwrap();
+    let mut pipeline = Pipeline::new(config);
+    let mut src = EventStream::new(capture);
+    let mut data = Default::default();
+    let mut errors = vec![];
+    loop {
+        match Reading::read(&mut src, &parser, config, &mut data) {
+            Ok((mut reading, field_errors)) => {
+                assert!(field_errors.is_empty(), "{:?}", field_errors);
+                if pipeline.apply(&mut reading).repeat.is_none() {
+                    sink.send(&reading).unwrap();
+                }
+            }
+            // The end of the capture.
+            Err(Error::Serial(_)) => return errors,
+            Err(e) => errors.push(e.to_string())
+        }
+    }
+}
+
+#[test]
+fn corrupt_capture() {
+    let config: Config = toml::from_str(r#"
+        [offsets]
+        total = -45
+
+        [[derived]]
+        key = "other"
+        from = "hot_water"
+        minus = ["total"]
+
+        [delta]
+        channels = ["other"]
+    "#).unwrap();
+    config.validate().unwrap();
+
+    let mut sink = Recording::default();
+    let errors = replay(include_bytes!("fixtures/corrupt.xml"), &config, &mut sink);
+
+    let readings: Vec<_> = sink.readings.iter()
+        .map(|reading| {
+            let watts: Vec<_> = reading.watts.iter()
+                .map(|(channel, &watts)| (&channel[..], watts))
+                .collect();
+            (reading.time.as_deref().unwrap(), watts, reading.deltas.get("other").copied())
+        })
+        .collect();
+    let watts = |total, other| {
+        vec![("hot_water", 2151.0), ("other", other), ("solar", 0.0), ("total", total)]
+    };
+    assert_eq!(readings, [
+        ("13:02:39", watts(300.0, 1851.0), None),
+        ("13:02:51", watts(306.0, 1845.0), Some(-6.0)),
+        ("13:02:57", watts(307.0, 1844.0), Some(-1.0)),
+        ("13:03:09", watts(309.0, 1842.0), Some(-2.0))
+    ]);
+    assert_eq!(errors.len(), 4, "{:?}", errors);
+}